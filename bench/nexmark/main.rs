@@ -18,34 +18,53 @@ use arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
 use driver::deploy::lambda;
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use nexmark::config::Config;
 use nexmark::event::{Auction, Bid, Person};
-use nexmark::NexMarkSource;
+use nexmark::{
+    assign_generators, query_streams, EventStream, NexMarkEvent, NexMarkSource, NexMarkStream,
+    EXECUTABLE_QUERIES,
+};
 use runtime::prelude::*;
-use rusoto_core::Region;
 use rusoto_lambda::{
-    CreateFunctionRequest, DeleteFunctionRequest, GetFunctionRequest, InvocationRequest,
-    InvocationResponse, Lambda, LambdaClient, PutFunctionConcurrencyRequest,
+    CreateFunctionRequest, DeleteFunctionConcurrencyRequest, DeleteFunctionRequest,
+    GetFunctionRequest, InvocationRequest, InvocationResponse, Lambda, LambdaClient,
+    PutFunctionConcurrencyRequest,
 };
-use serde_json::Value;
-use std::sync::Arc;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-#[allow(dead_code)]
 static LAMBDA_SYNC_CALL: &str = "RequestResponse";
-#[allow(dead_code)]
 static LAMBDA_ASYNC_CALL: &str = "Event";
 
+/// The number of attempts to reserve the concurrency of the lambda function.
+const CONCURRENCY_ATTEMPTS: u32 = 3;
+
+/// The NEXMark queries with built-in SQL statements. See [`query`].
+const NEXMARK_QUERIES: std::ops::RangeInclusive<usize> = 0..=9;
+
 lazy_static! {
-    static ref LAMBDA_CLIENT: LambdaClient = LambdaClient::new(Region::default());
+    static ref LAMBDA_CLIENT: LambdaClient = LambdaClient::new(config::aws_region());
 }
 
 #[derive(Debug, StructOpt)]
 struct NexmarkBenchmarkOpt {
     /// Query number
-    #[structopt(short, long)]
-    query: usize,
+    #[structopt(short, long, required_unless = "query_range")]
+    query: Option<usize>,
+
+    /// Run the queries of an inclusive range one after another, e.g. `1-8`,
+    /// and report the summary of each query
+    #[structopt(long = "query_range", conflicts_with_all = &["query", "sql_file"])]
+    query_range: Option<QueryRange>,
 
     /// Activate debug mode to see query results
     #[structopt(short, long)]
@@ -62,21 +81,354 @@ struct NexmarkBenchmarkOpt {
     /// Number of events generated among generators per second
     #[structopt(short = "e", long = "events_per_second", default_value = "100000")]
     events_per_second: usize,
+
+    /// Number of rows in each record batch converted from the events
+    #[structopt(short = "b", long = "batch_size", default_value = "1024")]
+    batch_size: usize,
+
+    /// Read the query from a SQL file instead of using the built-in query. The
+    /// query number still selects the events fed to the query.
+    #[structopt(long = "sql_file", parse(from_os_str))]
+    sql_file: Option<PathBuf>,
+
+    /// Seconds to wait for an invocation before the watchdog cancels it
+    #[structopt(long = "invocation_timeout", default_value = "900")]
+    invocation_timeout: u64,
+
+    /// Invoke the function once more after the watchdog cancels a stuck
+    /// invocation. The cancelled invocation may still run, so the events of a
+    /// respawned epoch can be processed twice
+    #[structopt(long)]
+    respawn: bool,
+
+    /// Append every function creation and invocation of the run to this file,
+    /// one JSON object per line
+    #[structopt(long = "invocation_log", parse(from_os_str))]
+    invocation_log: Option<PathBuf>,
+
+    /// Keep every generator invoking the function for this many seconds,
+    /// cycling through the generated events, instead of sending them in one
+    /// burst
+    #[structopt(long)]
+    duration: Option<u64>,
+
+    /// Number of invocations per second of each generator with `--duration`
+    #[structopt(long, default_value = "1")]
+    rate: f64,
+
+    /// Invoke the function once before the timed phase, so that the cold start
+    /// and the loading of the execution plan are not measured
+    #[structopt(long)]
+    warm: bool,
+
+    /// Invoke the function synchronously (`sync`) to report the response
+    /// payload and function error of every call, or asynchronously (`async`)
+    /// for throughput runs
+    #[structopt(long = "invocation_type", default_value = "async")]
+    invocation_type: InvocationType,
+
+    /// Feed each NEXMark stream to a source function of its own, and split the
+    /// generators among the streams by ratio, e.g. `bid=3,auction=1`. Each
+    /// source function runs the whole plan, so the query must read a single
+    /// stream, e.g. `bid=1` for q0-q2. By default, a single source function
+    /// gets the events of all streams
+    #[structopt(long)]
+    streams: Option<StreamRatios>,
+}
+
+/// An inclusive range of query numbers, such as `1-8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct QueryRange {
+    first: usize,
+    last:  usize,
+}
+
+impl QueryRange {
+    /// Returns the query numbers in the range.
+    fn queries(&self) -> std::ops::RangeInclusive<usize> {
+        self.first..=self.last
+    }
+}
+
+impl FromStr for QueryRange {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || SquirtleError::Internal(format!("Invalid query range: {}", s));
+        let mut bounds = s.trim().splitn(2, '-');
+        let first = bounds
+            .next()
+            .and_then(|q| q.parse::<usize>().ok())
+            .ok_or_else(invalid)?;
+        let last = match bounds.next() {
+            Some(q) => q.parse::<usize>().map_err(|_| invalid())?,
+            None => first,
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        Ok(QueryRange { first, last })
+    }
+}
+
+/// The NEXMark streams with a source function of their own, and the ratio of
+/// the generators that feed each of them, such as `bid=3,auction=1`. A stream
+/// without a ratio has a ratio of 1.
+#[derive(Debug, Clone, PartialEq)]
+struct StreamRatios(Vec<(EventStream, usize)>);
+
+impl FromStr for StreamRatios {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(|part| {
+                let mut parts = part.splitn(2, '=');
+                let stream = parts.next().unwrap_or_default().parse::<EventStream>()?;
+                let ratio = match parts.next() {
+                    Some(ratio) => ratio.trim().parse::<usize>().map_err(|_| {
+                        SquirtleError::Internal(format!("Invalid stream ratio: {}", part))
+                    })?,
+                    None => 1,
+                };
+                Ok((stream, ratio))
+            })
+            .collect::<Result<_>>()
+            .map(StreamRatios)
+    }
+}
+
+/// The source function that a generator invokes, and the only stream of the
+/// events it sends, if any.
+#[derive(Debug, Clone)]
+struct Source {
+    function: String,
+    stream:   Option<EventStream>,
+}
+
+impl Source {
+    /// Fetches the events that the generator sends for the given epoch.
+    fn select(
+        &self,
+        events: &NexMarkStream,
+        time: usize,
+        generator: usize,
+    ) -> Option<NexMarkEvent> {
+        match self.stream {
+            Some(stream) => events.select_stream(time, generator, stream),
+            None => events.select(time, generator),
+        }
+    }
+
+    /// Returns the number of events that the generator sends for the given
+    /// epoch.
+    fn num_events(&self, events: &NexMarkStream, time: usize, generator: usize) -> usize {
+        match self.stream {
+            Some(stream) => events.num_stream_events(time, generator, stream),
+            None => events.num_events(time, generator),
+        }
+    }
+}
+
+/// How the generators invoke the function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InvocationType {
+    /// Waits for the function to return its response.
+    Sync,
+    /// Returns as soon as the event is queued.
+    Async,
+}
+
+impl InvocationType {
+    /// Returns the `InvocationType` of the Lambda invocation request.
+    fn as_str(&self) -> &'static str {
+        match self {
+            InvocationType::Sync => LAMBDA_SYNC_CALL,
+            InvocationType::Async => LAMBDA_ASYNC_CALL,
+        }
+    }
+
+    /// Returns the maximum size of the invocation payload in bytes.
+    /// <https://docs.aws.amazon.com/lambda/latest/dg/gettingstarted-limits.html>
+    fn payload_limit(&self) -> usize {
+        match self {
+            InvocationType::Sync => 6 * 1024 * 1024,
+            InvocationType::Async => 256 * 1024,
+        }
+    }
+}
+
+impl FromStr for InvocationType {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "sync" => Ok(InvocationType::Sync),
+            "async" => Ok(InvocationType::Async),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown invocation type: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A log of the function creations and invocations of a run, one JSON object
+/// per line, to reconstruct the exact sequence of a failed run.
+struct InvocationLog {
+    file: Mutex<File>,
+}
+
+impl InvocationLog {
+    /// Opens the log at `path`, appending to it if it already exists.
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a request of `action` to `function_name`, sent at `sent`, and
+    /// its outcome.
+    fn append(
+        &self,
+        action: &str,
+        function_name: &str,
+        payload_size: usize,
+        sent: SystemTime,
+        status: Option<i64>,
+        error: Option<String>,
+    ) {
+        let entry = json!({
+            "timestamp": sent.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "elapsed_ms": sent.elapsed().unwrap_or_default().as_millis() as u64,
+            "action": action,
+            "function_name": function_name,
+            "payload_size": payload_size,
+            "status": status,
+            "error": error,
+        });
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", entry) {
+            warn!("Failed to append to the invocation log: {}", e);
+        }
+    }
+}
+
+/// The summary of a benchmark run.
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    /// Query number
+    pub query_number: usize,
+    /// Number of generators of the run
+    pub generators:   usize,
+    /// Number of seconds of generated events
+    pub seconds:      usize,
+    /// Number of invocations that returned a response
+    pub invoked:      usize,
+    /// Number of generator tasks that failed
+    pub failed:       usize,
+    /// Number of invocations cancelled by the watchdog
+    pub stuck:        usize,
+    /// Number of synchronous invocations that returned a function error
+    pub errors:       usize,
+    /// Number of retries reported by the synchronous invocations
+    pub retries:      usize,
+    /// Number of events sent by each generator
+    pub events:       Vec<usize>,
+    /// Total size of the invocation payloads sent, in bytes
+    pub payload_size: usize,
+    /// Wall-clock time of the warm-up invocation, if any
+    pub warmup:       Option<Duration>,
+    /// Wall-clock time to send all the events
+    pub duration:     Duration,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Query:       q{}", self.query_number)?;
+        writeln!(f, "Generators:  {}", self.generators)?;
+        writeln!(f, "Seconds:     {}", self.seconds)?;
+        writeln!(f, "Invoked:     {}", self.invoked)?;
+        writeln!(f, "Failed:      {}", self.failed)?;
+        writeln!(f, "Stuck:       {}", self.stuck)?;
+        writeln!(f, "Errors:      {}", self.errors)?;
+        writeln!(f, "Retries:     {}", self.retries)?;
+        writeln!(
+            f,
+            "Events:      {} {:?}",
+            self.events.iter().sum::<usize>(),
+            self.events
+        )?;
+        writeln!(f, "Payload:     {} bytes", self.payload_size)?;
+        if let Some(warmup) = self.warmup {
+            writeln!(f, "Warm-up:     {:?}", warmup)?;
+        }
+        write!(f, "Duration:    {:?}", self.duration)
+    }
+}
+
+/// What a generator task sent and received.
+#[derive(Debug, Default)]
+struct GeneratorReport {
+    /// The generator, i.e., the source identifier of the events
+    generator:    usize,
+    /// The responses of the invocations
+    responses:    Vec<InvocationResponse>,
+    /// Number of invocations cancelled by the watchdog
+    stuck:        usize,
+    /// Number of events sent
+    events:       usize,
+    /// Total size of the invocation payloads sent, in bytes
+    payload_size: usize,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    benchmark(NexmarkBenchmarkOpt::from_args()).await?;
+    let opt = NexmarkBenchmarkOpt::from_args();
+    let queries: Vec<usize> = match (opt.query_range, opt.query) {
+        // The unsupported queries of a range are skipped.
+        (Some(range), _) => range
+            .queries()
+            .filter(|&query| match check_query(query) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("{}", e);
+                    false
+                }
+            })
+            .collect(),
+        (None, Some(query)) => {
+            check_query(query)?;
+            vec![query]
+        }
+        (None, None) => unreachable!("structopt requires --query or --query_range"),
+    };
+
+    // A failed query of a range doesn't stop the remaining queries. Each query
+    // has its own function, named after the query code, so the runs don't
+    // interfere with each other.
+    let mut summaries = vec![];
+    for query in queries {
+        match benchmark(&opt, query).await {
+            Ok(summary) => summaries.push(summary),
+            Err(e) if opt.query_range.is_some() => warn!("Query q{} failed: {}", query, e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let summaries: Vec<_> = summaries.iter().map(|s| s.to_string()).collect();
+    println!("{}", summaries.join("\n\n"));
     Ok(())
 }
 
-async fn benchmark(opt: NexmarkBenchmarkOpt) -> Result<()> {
+async fn benchmark(opt: &NexmarkBenchmarkOpt, query_number: usize) -> Result<RunSummary> {
     println!("Running benchmarks with the following options: {:?}", opt);
     let mut config = Config::new();
     config.insert("threads", opt.generators.to_string());
     config.insert("seconds", opt.seconds.to_string());
     config.insert("events-per-second", opt.events_per_second.to_string());
+    config.insert("batch-size", opt.batch_size.to_string());
     let nexmark = NexMarkSource {
         config,
         ..Default::default()
@@ -108,119 +460,420 @@ async fn benchmark(opt: NexmarkBenchmarkOpt) -> Result<()> {
     }
 
     // marshal physical plan into cloud environment
-    let sqls = query(opt.query);
-    if sqls.len() > 1 {
-        unimplemented!();
-    }
+    let sqls = match &opt.sql_file {
+        Some(path) => vec![std::fs::read_to_string(path)?],
+        None => query(query_number),
+    };
+    // The planner rejects any table other than the registered NEXMark tables.
+    let query_plan = physical_plan(&mut ctx, &sqls[0]).map_err(|e| match &opt.sql_file {
+        Some(path) => SquirtleError::Plan(format!("{}: {}", path.display(), e)),
+        None => e,
+    })?;
     let lambda_ctx = ExecutionContext {
-        plan:         physical_plan(&mut ctx, &sqls[0])?,
-        name:         format!("q{}", opt.query),
+        plan:         query_plan,
+        name:         FunctionName::new(&query_code(&sqls[0]), 0).to_string(),
         next:         CloudFunction::None,
-        datasource:   DataSource::default(),
-        query_number: Some(opt.query),
+        datasource:   DataSource::NexMarkEvent(nexmark.clone()),
+        query_number: Some(query_number),
         debug:        opt.debug,
+        shuffle_keys: vec![],
+        flags:        HashMap::new(),
+        watermark:    None,
+    };
+
+    // tag the lambda function with the query number and the run id to attribute
+    // the cost per experiment.
+    let run_id = SystemClock
+        .now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut tags = HashMap::new();
+    tags.insert("query".to_owned(), format!("q{}", query_number));
+    tags.insert("run_id".to_owned(), run_id.to_string());
+
+    let log = match &opt.invocation_log {
+        Some(path) => Some(Arc::new(InvocationLog::open(path)?)),
+        None => None,
     };
 
     // create lambda function based on the generic lambda function code on AWS S3.
-    let func_arn = create_lambda_function(&lambda_ctx).await?;
-    info!("[OK] Create lambda function {}.", func_arn);
+    let sources = match &opt.streams {
+        None => {
+            let func_arn = create_lambda_function(&lambda_ctx, &tags, log.as_deref()).await?;
+            info!("[OK] Create lambda function {}.", func_arn);
+            vec![
+                Source {
+                    function: func_arn,
+                    stream:   None,
+                };
+                opt.generators
+            ]
+        }
+        Some(StreamRatios(ratios)) => {
+            // A stream's source function only gets the events of its stream,
+            // so it can't run a plan that joins several streams.
+            let streams = query_streams(query_number);
+            if streams.len() != 1 {
+                return Err(SquirtleError::Internal(format!(
+                    "--streams needs a query over a single stream, but q{} reads {:?}.",
+                    query_number, streams
+                )));
+            }
+            if let Some((stream, _)) = ratios.iter().find(|(s, _)| !streams.contains(s)) {
+                return Err(SquirtleError::Internal(format!(
+                    "q{} doesn't read the {} stream.",
+                    query_number, stream
+                )));
+            }
+
+            // The source functions of the streams share the plan, and are
+            // named like the members of a function group.
+            let mut functions = HashMap::new();
+            for (i, (stream, _)) in ratios.iter().enumerate() {
+                let ctx = ExecutionContext {
+                    name: FunctionName::new(&query_code(&sqls[0]), 0)
+                        .with_group(i)
+                        .to_string(),
+                    ..lambda_ctx.clone()
+                };
+                let func_arn = create_lambda_function(&ctx, &tags, log.as_deref()).await?;
+                info!("[OK] Create lambda function {} for {}s.", func_arn, stream);
+                functions.insert(*stream, func_arn);
+            }
+            assign_generators(opt.generators, ratios)?
+                .into_iter()
+                .map(|stream| Source {
+                    function: functions[&stream].clone(),
+                    stream:   Some(stream),
+                })
+                .collect()
+        }
+    };
+    // The generators of a source function are consecutive.
+    let mut functions: Vec<String> = sources.iter().map(|s| s.function.clone()).collect();
+    functions.dedup();
 
     let events = Arc::new(nexmark.generate_data()?);
     info!("[OK] Generate nexmark events.");
 
+    let timeout = Duration::from_secs(opt.invocation_timeout);
+    let warmup = if opt.warm {
+        let start = Instant::now();
+        for func_arn in &functions {
+            let response = invoke_with_watchdog(
+                func_arn.clone(),
+                serde_json::to_vec(&json!({ "warm": true }))?,
+                InvocationType::Sync,
+                timeout,
+                false,
+                log.as_deref(),
+            )
+            .await?
+            .ok_or_else(|| {
+                SquirtleError::Execution("The warm-up invocation got stuck.".to_owned())
+            })?;
+            if let Some(error) = response.function_error {
+                return Err(SquirtleError::Execution(format!(
+                    "The warm-up invocation failed: {}",
+                    error
+                )));
+            }
+            info!("[OK] Warm up lambda function {}.", func_arn);
+        }
+        Some(start.elapsed())
+    } else {
+        None
+    };
+
     #[allow(unused_assignments)]
     let mut tasks = vec![];
 
-    if let StreamWindow::None = nexmark.window {
+    let start = Instant::now();
+    let respawn = opt.respawn;
+    if respawn {
+        warn!("Respawned invocations may process the same events twice.");
+    }
+    let invocation_type = opt.invocation_type;
+
+    if let Some(duration) = opt.duration {
+        if opt.rate.is_nan() || opt.rate <= 0.0 {
+            return Err(SquirtleError::Internal(format!(
+                "The invocation rate must be positive: {}",
+                opt.rate
+            )));
+        }
+        // the epochs are cycled through, which needs at least one of them.
+        if opt.seconds == 0 {
+            return Err(SquirtleError::Internal(
+                "--duration needs at least one second of generated events.".to_string(),
+            ));
+        }
+        if nexmark.window != StreamWindow::None {
+            for func_arn in &functions {
+                reserve_lambda_concurrency(func_arn, 1).await;
+            }
+        }
+        let deadline = start + Duration::from_secs(duration);
+        let interval = Duration::from_secs_f64(1.0 / opt.rate);
+        tasks = (0..opt.generators)
+            .map(|g| {
+                let source = sources[g].clone();
+                let seconds = opt.seconds;
+                let events = events.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let mut report = GeneratorReport {
+                        generator: g,
+                        ..Default::default()
+                    };
+                    let mut next = Instant::now();
+                    // cycle through the generated epochs until the duration elapses.
+                    for t in 0.. {
+                        if let Some(mut event) = source.select(&events, t % seconds, g) {
+                            event.epoch = t;
+                            info!("[OK] Send nexmark event (time: {}, source: {}).", t, g);
+                            report.events += source.num_events(&events, t % seconds, g);
+                            let payload = serde_json::to_vec(&event)?;
+                            report.payload_size += payload.len();
+                            match invoke_with_watchdog(
+                                source.function.clone(),
+                                payload,
+                                invocation_type,
+                                timeout,
+                                respawn,
+                                log.as_deref(),
+                            )
+                            .await?
+                            {
+                                Some(r) => report.responses.push(r),
+                                None => report.stuck += 1,
+                            }
+                        }
+                        // no invocation starts after the duration elapses, even if
+                        // the invocations are slower than the rate.
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        // a generator that fell behind resumes at the rate from
+                        // now on rather than bursting to catch up.
+                        next = (next + interval).max(now);
+                        if next >= deadline {
+                            break;
+                        }
+                        tokio::time::sleep_until(next.into()).await;
+                    }
+                    Ok(report)
+                })
+            })
+            // this collect *is needed* so that the join below can switch between tasks.
+            .collect::<Vec<tokio::task::JoinHandle<Result<GeneratorReport>>>>();
+    } else if let StreamWindow::None = nexmark.window {
         tasks = iproduct!(0..opt.seconds, 0..opt.generators)
             .map(|(t, g)| {
-                let func_arn = func_arn.clone();
+                let source = sources[g].clone();
                 let events = events.clone();
+                let log = log.clone();
                 tokio::spawn(async move {
                     info!("[OK] Send nexmark event (time: {}, source: {}).", t, g);
-                    let response = vec![
-                        invoke_lambda_function(
-                            func_arn,
-                            serde_json::to_vec(&events.select(t, g).ok_or_else(|| {
-                                SquirtleError::Internal(
-                                    "Failed to select event from streaming data".to_string(),
-                                )
-                            })?)?,
-                            LAMBDA_SYNC_CALL,
-                        )
-                        .await?,
-                    ];
-                    Ok(response)
+                    let payload =
+                        serde_json::to_vec(&source.select(&events, t, g).ok_or_else(|| {
+                            SquirtleError::Internal(
+                                "Failed to select event from streaming data".to_string(),
+                            )
+                        })?)?;
+                    let mut report = GeneratorReport {
+                        generator: g,
+                        events: source.num_events(&events, t, g),
+                        payload_size: payload.len(),
+                        ..Default::default()
+                    };
+                    match invoke_with_watchdog(
+                        source.function,
+                        payload,
+                        invocation_type,
+                        timeout,
+                        respawn,
+                        log.as_deref(),
+                    )
+                    .await?
+                    {
+                        Some(r) => report.responses.push(r),
+                        None => report.stuck += 1,
+                    }
+                    Ok(report)
                 })
             })
             // this collect *is needed* so that the join below can switch between tasks.
-            .collect::<Vec<tokio::task::JoinHandle<Result<Vec<InvocationResponse>>>>>();
+            .collect::<Vec<tokio::task::JoinHandle<Result<GeneratorReport>>>>();
     } else {
-        set_lambda_concurrency(func_arn.clone(), 1).await?;
+        for func_arn in &functions {
+            reserve_lambda_concurrency(func_arn, 1).await;
+        }
         tasks = (0..opt.generators)
             .map(|g| {
-                let func_arn = func_arn.clone();
+                let source = sources[g].clone();
                 let seconds = opt.seconds;
                 let events = events.clone();
+                let log = log.clone();
                 tokio::spawn(async move {
-                    let mut response = vec![];
+                    let mut report = GeneratorReport {
+                        generator: g,
+                        ..Default::default()
+                    };
                     for t in 0..seconds {
-                        let event = events.select(t, g).unwrap();
+                        // a generator of a single stream may have no events in an epoch.
+                        let event = match source.select(&events, t, g) {
+                            Some(event) => event,
+                            None => continue,
+                        };
                         info!("[OK] Send nexmark event (time: {}, source: {}).", t, g);
-                        response.push(
-                            invoke_lambda_function(
-                                func_arn.clone(),
-                                serde_json::to_vec(&event)?,
-                                LAMBDA_ASYNC_CALL,
-                            )
-                            .await?,
-                        );
+                        report.events += source.num_events(&events, t, g);
+                        let payload = serde_json::to_vec(&event)?;
+                        report.payload_size += payload.len();
+                        match invoke_with_watchdog(
+                            source.function.clone(),
+                            payload,
+                            invocation_type,
+                            timeout,
+                            respawn,
+                            log.as_deref(),
+                        )
+                        .await?
+                        {
+                            Some(r) => report.responses.push(r),
+                            None => report.stuck += 1,
+                        }
                     }
-                    Ok(response)
+                    Ok(report)
                 })
             })
             // this collect *is needed* so that the join below can switch between tasks.
-            .collect::<Vec<tokio::task::JoinHandle<Result<Vec<InvocationResponse>>>>>();
+            .collect::<Vec<tokio::task::JoinHandle<Result<GeneratorReport>>>>();
     }
 
+    let mut summary = RunSummary {
+        query_number,
+        generators: opt.generators,
+        seconds: opt.seconds,
+        events: vec![0; opt.generators],
+        warmup,
+        ..Default::default()
+    };
     for task in tasks {
-        let res_vec = task.await.expect("Lambda function execution failed.")?;
-        if opt.debug {
-            let _res = res_vec
-                .into_iter()
-                .map(|response| {
-                    // The HTTP status code is in the 200 range for a successful request.
-                    // - For the RequestResponse invocation type, this status code is 200.
-                    // - For the Event invocation type, this status code is 202.
-                    // - For the DryRun invocation type, the status code is 204.
-                    match response.status_code {
-                        Some(200) => {
-                            info!(
-                                "{:?}",
-                                serde_json::from_slice::<Value>(&response.payload.ok_or_else(
-                                    || {
-                                        SquirtleError::Internal(
-                                            "Failed to parse the payload of the function response."
-                                                .to_string(),
-                                        )
-                                    }
-                                )?)?
-                            );
-                        }
-                        Some(202) => {
-                            info!(" [OK] Received status from async lambda function.");
-                        }
-                        _ => {
-                            panic!("Incorrect Lambda invocation!");
-                        }
-                    }
-                    Ok(())
-                })
-                .collect::<Vec<Result<()>>>();
+        let report = match task.await.expect("Lambda function execution failed.") {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Generator task failed: {}", e);
+                summary.failed += 1;
+                continue;
+            }
+        };
+        let generator = report.generator;
+        summary.invoked += report.responses.len();
+        summary.stuck += report.stuck;
+        summary.events[generator] += report.events;
+        summary.payload_size += report.payload_size;
+        for response in report.responses {
+            let payload = response
+                .payload
+                .as_ref()
+                .map(|p| String::from_utf8_lossy(p).into_owned())
+                .unwrap_or_default();
+            match (invocation_type, &response.function_error) {
+                (InvocationType::Sync, Some(error)) => {
+                    summary.errors += 1;
+                    warn!("[ERROR] Generator {}: {}: {}", generator, error, payload);
+                }
+                (InvocationType::Sync, None) => {
+                    info!("[OK] Generator {}: {}", generator, payload);
+                    summary.retries += serde_json::from_str::<serde_json::Value>(&payload)
+                        .ok()
+                        .and_then(|v| v["retries"].as_u64())
+                        .unwrap_or_default() as usize;
+                }
+                (InvocationType::Async, _) if opt.debug => {
+                    info!(" [OK] Received status from async lambda function.");
+                }
+                (InvocationType::Async, _) => {}
+            }
         }
     }
 
-    Ok(())
+    summary.duration = start.elapsed();
+
+    if summary.stuck > 0 {
+        warn!(
+            "The watchdog cancelled {} stuck invocation(s) after {:?}.",
+            summary.stuck, timeout
+        );
+    }
+
+    // the reserved concurrency of each query is taken from the account's pool,
+    // so it is given back before the next query of the range reserves its own.
+    if opt.query_range.is_some() {
+        for func_arn in &functions {
+            release_lambda_concurrency(func_arn).await;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Invokes the lambda function under a watchdog. If the invocation doesn't
+/// return within `timeout`, it is cancelled and, if `respawn` is set, invoked
+/// once more. Returns `None` if the invocation got stuck every time.
+///
+/// A cancelled invocation may still have reached the function, so a respawned
+/// one can deliver the same events twice. The function doesn't deduplicate
+/// them: the output of a respawned epoch may be counted twice.
+///
+/// Each attempt is appended to the invocation log, if any. Events that exceed
+/// the payload limit of the invocation type are rejected before sending.
+async fn invoke_with_watchdog(
+    function_name: String,
+    events: Vec<u8>,
+    invocation_type: InvocationType,
+    timeout: Duration,
+    respawn: bool,
+    log: Option<&InvocationLog>,
+) -> Result<Option<InvocationResponse>> {
+    if events.len() > invocation_type.payload_limit() {
+        return Err(SquirtleError::Execution(format!(
+            "The payload of {} bytes exceeds the {} byte limit of {:?} invocations.",
+            events.len(),
+            invocation_type.payload_limit(),
+            invocation_type
+        )));
+    }
+
+    let attempts = if respawn { 2 } else { 1 };
+    for attempt in 1..=attempts {
+        let sent = SystemClock.now();
+        let invocation = invoke_lambda_function(
+            function_name.clone(),
+            events.clone(),
+            invocation_type.as_str(),
+        );
+        let response = tokio::time::timeout(timeout, invocation).await;
+        if let Some(log) = log {
+            let (status, error) = match &response {
+                Ok(Ok(response)) => (response.status_code, response.function_error.clone()),
+                Ok(Err(e)) => (None, Some(e.to_string())),
+                Err(_) => (None, Some(format!("Stuck for {:?}", timeout))),
+            };
+            log.append("invoke", &function_name, events.len(), sent, status, error);
+        }
+        match response {
+            Ok(response) => return response.map(Some),
+            Err(_) => warn!(
+                "Invocation of {} got stuck for {:?} (attempt {}/{}).",
+                function_name, timeout, attempt, attempts
+            ),
+        }
+    }
+    Ok(None)
 }
 
 /// Invoke the lambda function with the nexmark events.
@@ -248,6 +901,32 @@ async fn invoke_lambda_function(
     }
 }
 
+/// Reserves the lambda function's concurrency, retrying a few times.
+///
+/// The function already exists at this point, so a failure, e.g. because the
+/// account's unreserved concurrency is exhausted, doesn't abort the run. It is
+/// logged instead: without the reservation, the events of a window may be
+/// processed by more than one function instance.
+async fn reserve_lambda_concurrency(function_name: &str, concurrency: i64) {
+    for attempt in 0..CONCURRENCY_ATTEMPTS {
+        match set_lambda_concurrency(function_name.to_owned(), concurrency).await {
+            Ok(()) => return,
+            Err(e) if attempt + 1 < CONCURRENCY_ATTEMPTS => {
+                warn!(
+                    "Failed to set the concurrency of {}: {}, retry ...",
+                    function_name, e
+                );
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(e) => warn!(
+                "Failed to set the concurrency of {} to {}: {}. The function is invoked without \
+                 reserved concurrency.",
+                function_name, concurrency, e
+            ),
+        }
+    }
+}
+
 /// Set the lambda function's concurrency.
 /// <https://docs.aws.amazon.com/lambda/latest/dg/configuration-concurrency.html>
 async fn set_lambda_concurrency(function_name: String, concurrency: i64) -> Result<()> {
@@ -259,12 +938,36 @@ async fn set_lambda_concurrency(function_name: String, concurrency: i64) -> Resu
         .put_function_concurrency(request)
         .await
         .map_err(|e| SquirtleError::Internal(e.to_string()))?;
-    assert_ne!(concurrency.reserved_concurrent_executions, Some(0));
+    if concurrency.reserved_concurrent_executions == Some(0) {
+        return Err(SquirtleError::Internal(
+            "The reserved concurrency is 0, which throttles every invocation.".to_owned(),
+        ));
+    }
     Ok(())
 }
 
-/// Creates a single lambda function using bootstrap.zip in Amazon S3.
-async fn create_lambda_function(ctx: &ExecutionContext) -> Result<String> {
+/// Releases the reserved concurrency of the lambda function back to the
+/// account's unreserved pool. A failure is logged rather than aborting the
+/// run.
+async fn release_lambda_concurrency(function_name: &str) {
+    let request = DeleteFunctionConcurrencyRequest {
+        function_name: function_name.to_owned(),
+    };
+    if let Err(e) = LAMBDA_CLIENT.delete_function_concurrency(request).await {
+        warn!(
+            "Failed to release the reserved concurrency of {}: {}",
+            function_name, e
+        );
+    }
+}
+
+/// Creates a single lambda function using bootstrap.zip in Amazon S3. The
+/// creation is appended to the invocation log, if any.
+async fn create_lambda_function(
+    ctx: &ExecutionContext,
+    tags: &HashMap<String, String>,
+    log: Option<&InvocationLog>,
+) -> Result<String> {
     if LAMBDA_CLIENT
         .get_function(GetFunctionRequest {
             function_name: ctx.name.clone(),
@@ -283,19 +986,32 @@ async fn create_lambda_function(ctx: &ExecutionContext) -> Result<String> {
             .map_err(|e| SquirtleError::Internal(e.to_string()))?;
     }
 
-    match LAMBDA_CLIENT
+    let environment = lambda::environment(&ctx)?;
+    let environment_size = environment
+        .as_ref()
+        .and_then(|env| env.variables.as_ref())
+        .map(|vars| vars.iter().map(|(k, v)| k.len() + v.len()).sum())
+        .unwrap_or_default();
+    let sent = SystemClock.now();
+    let created = LAMBDA_CLIENT
         .create_function(CreateFunctionRequest {
             code: lambda::nexmark_function_code(),
-            environment: lambda::environment(&ctx),
+            environment,
             function_name: ctx.name.clone(),
             handler: lambda::handler(),
             memory_size: lambda::memory_size(&ctx),
             role: lambda::role().await,
             runtime: lambda::runtime(),
+            tags: lambda::tags(tags)?,
             ..Default::default()
         })
-        .await
-    {
+        .await;
+    if let Some(log) = log {
+        let error = created.as_ref().err().map(|e| e.to_string());
+        log.append("create", &ctx.name, environment_size, sent, None, error);
+    }
+
+    match created {
         Ok(config) => {
             return config.function_arn.ok_or_else(|| {
                 SquirtleError::Internal("Unable to find lambda function arn.".to_string())
@@ -310,7 +1026,34 @@ async fn create_lambda_function(ctx: &ExecutionContext) -> Result<String> {
     }
 }
 
-/// Returns Nextmark query strings based on the query number.
+/// Returns the queries that the benchmark runs: the NEXMark queries with a
+/// single SQL statement that the cloud function can also feed with events.
+fn supported_queries() -> Vec<usize> {
+    NEXMARK_QUERIES
+        .filter(|q| EXECUTABLE_QUERIES.contains(q))
+        .filter(|&q| query(q).len() == 1)
+        .collect()
+}
+
+/// Returns an error that lists the supported queries if `query` isn't one of
+/// them.
+fn check_query(query: usize) -> Result<()> {
+    let supported = supported_queries();
+    if supported.contains(&query) {
+        return Ok(());
+    }
+    Err(SquirtleError::Internal(format!(
+        "Unsupported query: q{}. The supported queries are {}.",
+        query,
+        supported
+            .iter()
+            .map(|q| format!("q{}", q))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+/// Returns the SQL statements of a query in [`NEXMARK_QUERIES`].
 fn query(query: usize) -> Vec<String> {
     match query {
         0 => vec!["SELECT * FROM bid"],
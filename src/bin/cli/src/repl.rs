@@ -14,7 +14,6 @@
 
 use clap::{crate_version, App, Arg};
 use futures::executor::block_on;
-use rusoto_core::Region;
 use rusoto_s3::PutObjectRequest;
 use rusoto_s3::{S3Client, S3};
 use rustyline::Editor;
@@ -183,7 +182,7 @@ pub fn put_object_to_s3(bucket: &str, key: &str, obj_path: &str) -> Result<(), E
             ..Default::default()
         };
 
-        let client = S3Client::new(Region::default());
+        let client = S3Client::new(runtime::config::aws_region());
         block_on(client.put_object(put_obj_req))?;
         rainbow_println("[OK] Upload Succeed.");
     } else {
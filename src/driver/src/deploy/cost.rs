@@ -0,0 +1,137 @@
+// Copyright (c) 2020 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+// Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! A rough estimate of what a query deployment costs on AWS Lambda, to budget
+//! experiments before deploying anything.
+
+use crate::deploy::lambda;
+use crate::funcgen::function::QueryFlow;
+use daggy::NodeIndex;
+use std::time::Duration;
+
+/// The prices and workload assumptions of a cost estimate. The prices change
+/// over time and vary by region, so they are parameters rather than constants.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostParams {
+    /// The price of one GB-second of function duration, in dollars.
+    pub gb_second_price:     f64,
+    /// The price of one function request, in dollars.
+    pub request_price:       f64,
+    /// The price of one GB of data transfer, in dollars.
+    pub data_transfer_price: f64,
+    /// The assumed duration of one invocation.
+    pub duration:            Duration,
+    /// The assumed number of invocations of each function over the run.
+    pub invocations:         usize,
+    /// The assumed data transfer over the run, in GB.
+    pub data_transfer:       f64,
+}
+
+impl Default for CostParams {
+    /// The x86 prices in us-east-1 as of 2021, for one invocation of each
+    /// function that lasts one second.
+    fn default() -> Self {
+        Self {
+            gb_second_price:     0.000_016_666_7,
+            request_price:       0.000_000_2,
+            data_transfer_price: 0.09,
+            duration:            Duration::from_secs(1),
+            invocations:         1,
+            data_transfer:       0.0,
+        }
+    }
+}
+
+/// The estimated cost of a query deployment, in dollars.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CostEstimate {
+    /// The cost of the function duration.
+    pub compute:       f64,
+    /// The cost of the function requests.
+    pub requests:      f64,
+    /// The cost of the data transfer.
+    pub data_transfer: f64,
+}
+
+impl CostEstimate {
+    /// The total estimated cost, in dollars.
+    pub fn total(&self) -> f64 {
+        self.compute + self.requests + self.data_transfer
+    }
+}
+
+/// Estimates the cost of running the query of `flow` with the configured
+/// memory size of each function and the assumptions of `params`.
+pub fn estimate(flow: &QueryFlow, params: &CostParams) -> CostEstimate {
+    let summary = flow.topology_summary();
+    let invocations = params.invocations as f64;
+
+    let gb_seconds: f64 = summary
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, functions)| {
+            let memory_mb = lambda::memory_size(&flow.ctx[&NodeIndex::new(i)]).unwrap_or(128);
+            *functions as f64 * memory_mb as f64 / 1024.0 * params.duration.as_secs_f64()
+        })
+        .sum();
+
+    CostEstimate {
+        compute:       gb_seconds * invocations * params.gb_second_price,
+        requests:      summary.function_count() as f64 * invocations * params.request_price,
+        data_transfer: params.data_transfer * params.data_transfer_price,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use runtime::prelude::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn estimate_cost() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let flow = QueryFlow::from_sql(
+            "SELECT b, COUNT(a) FROM t GROUP BY b",
+            &[("t", schema)],
+            DataSource::UnknownEvent,
+            None,
+        )?;
+        assert_eq!(10, flow.topology_summary().function_count());
+
+        let params = CostParams {
+            gb_second_price:     0.01,
+            request_price:       0.001,
+            data_transfer_price: 0.5,
+            duration:            Duration::from_secs(2),
+            invocations:         100,
+            data_transfer:       2.0,
+        };
+        let cost = flow.estimate_cost(&params);
+
+        // 10 functions x 128 MB x 2 s x 100 invocations = 250 GB-seconds.
+        assert!((cost.compute - 2.5).abs() < 1e-9);
+        // 10 functions x 100 invocations = 1000 requests.
+        assert!((cost.requests - 1.0).abs() < 1e-9);
+        assert!((cost.data_transfer - 1.0).abs() < 1e-9);
+        assert!((cost.total() - 4.5).abs() < 1e-9);
+
+        Ok(())
+    }
+}
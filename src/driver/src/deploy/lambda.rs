@@ -16,10 +16,14 @@
 
 use crate::funcgen::dag::*;
 use runtime::prelude::*;
-use rusoto_core::Region;
+use rusoto_core::RusotoError;
 use rusoto_iam::{GetRoleRequest, Iam, IamClient};
-use rusoto_lambda::{Environment, FunctionCode};
+use rusoto_lambda::{
+    Environment, FunctionCode, Lambda, LambdaClient, ListFunctionsError, ListFunctionsRequest,
+};
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 
@@ -128,17 +132,115 @@ pub fn nexmark_function_code() -> FunctionCode {
     }
 }
 
+/// The environment variable that holds the fingerprint of the function's
+/// execution plan.
+pub const PLAN_FINGERPRINT: &str = "plan_fingerprint";
+
 /// Environment variables that are accessible from function code during
 /// execution.
-pub fn environment(ctx: &ExecutionContext) -> Option<Environment> {
+pub fn environment(ctx: &ExecutionContext) -> Result<Option<Environment>> {
     let mut map = HashMap::new();
     map.insert(
         (&globals["lambda"]["name"]).to_owned(),
-        ctx.marshal(Encoding::Zstd),
+        ctx.marshal(Encoding::Zstd)?,
     );
-    Some(Environment {
+    map.insert(PLAN_FINGERPRINT.to_owned(), ctx.fingerprint()?);
+    Ok(Some(Environment {
         variables: Some(map),
-    })
+    }))
+}
+
+/// How a function is deployed, compared to the currently-deployed function
+/// with the same name.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Deployment {
+    /// The function doesn't exist yet, so it must be created.
+    Create,
+    /// The function exists but its execution context changed, so its
+    /// configuration must be updated.
+    Update,
+    /// The function exists with the same execution context, so it is skipped.
+    Unchanged,
+}
+
+/// Decides how to deploy the function of `ctx`, given the environment
+/// variables of the currently-deployed function (`None` if there is no such
+/// function).
+///
+/// The plan fingerprints are compared first. If the plans match, the whole
+/// deployed context is compared as well, because a change to any other field
+/// (such as `name` or `next`) still requires an update. `debug` is compared on
+/// its own, since the equality of contexts leaves it out. A deployed context
+/// that can't be unmarshaled is replaced.
+pub fn deployment(
+    ctx: &ExecutionContext,
+    deployed: Option<&HashMap<String, String>>,
+) -> Result<Deployment> {
+    let vars = match deployed {
+        Some(vars) => vars,
+        None => return Ok(Deployment::Create),
+    };
+
+    if vars.get(PLAN_FINGERPRINT) != Some(&ctx.fingerprint()?) {
+        return Ok(Deployment::Update);
+    }
+
+    match vars
+        .get(&globals["lambda"]["name"])
+        .map(|env| ExecutionContext::unmarshal(env))
+    {
+        Some(Ok(deployed)) if deployed == *ctx && deployed.debug == ctx.debug => {
+            Ok(Deployment::Unchanged)
+        }
+        _ => Ok(Deployment::Update),
+    }
+}
+
+/// The maximum number of tags on a Lambda function.
+pub const MAX_TAGS: usize = 50;
+/// The maximum length of a tag key, in Unicode characters.
+pub const MAX_TAG_KEY_LEN: usize = 128;
+/// The maximum length of a tag value, in Unicode characters.
+pub const MAX_TAG_VALUE_LEN: usize = 256;
+
+/// The tags to apply to the function, such as the experiment or the query the
+/// function belongs to, for cost allocation.
+///
+/// Returns an error if the tags exceed the limits of AWS tagging:
+/// <https://docs.aws.amazon.com/general/latest/gr/aws_tagging.html>
+pub fn tags(tags: &HashMap<String, String>) -> Result<Option<HashMap<String, String>>> {
+    if tags.is_empty() {
+        return Ok(None);
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(SquirtleError::FunctionGeneration(format!(
+            "Too many tags: {} (at most {}).",
+            tags.len(),
+            MAX_TAGS
+        )));
+    }
+    for (key, value) in tags {
+        let key_len = key.chars().count();
+        if key_len == 0 || key_len > MAX_TAG_KEY_LEN {
+            return Err(SquirtleError::FunctionGeneration(format!(
+                "Invalid tag key '{}': the length must be between 1 and {}.",
+                key, MAX_TAG_KEY_LEN
+            )));
+        }
+        if key.to_lowercase().starts_with("aws:") {
+            return Err(SquirtleError::FunctionGeneration(format!(
+                "Invalid tag key '{}': the prefix 'aws:' is reserved.",
+                key
+            )));
+        }
+        if value.chars().count() > MAX_TAG_VALUE_LEN {
+            return Err(SquirtleError::FunctionGeneration(format!(
+                "Invalid value of tag '{}': the length must be at most {}.",
+                key, MAX_TAG_VALUE_LEN
+            )));
+        }
+    }
+    Ok(Some(tags.clone()))
 }
 
 /// The name of the Lambda function.
@@ -152,9 +254,9 @@ pub fn environment(ctx: &ExecutionContext) -> Option<Environment> {
 /// The length constraint applies only to the full ARN. If you
 /// specify only the function name, it is limited to 64 characters in length.
 ///
-/// - If the next call is `CloudFunction::None`, then the current lambda
-///   function's concurrency = 1 and its type is `CloudFunction::Chorus((name,
-///   group_size))`.
+/// - If the next call is `CloudFunction::None` or `CloudFunction::Sink(..)`,
+///   then the current lambda function's concurrency = 1 and its type is
+///   `CloudFunction::Chorus((name, group_size))`.
 ///
 /// - If the next call is `CloudFunction::Chorus(..)`, then the current lambda
 ///   function's concurrency > 1 (default = 8) and its type is
@@ -163,19 +265,19 @@ pub fn environment(ctx: &ExecutionContext) -> Option<Environment> {
 /// - If the next call is `CloudFunction::Solo(..)`, then the current lambda
 ///   function's concurrency = 1 and its type is `CloudFunction::Chorus((name,
 ///   group_size))`.
+///
+/// - If the [`NO_GROUP_FLAG`] is enabled, there is no function group at all.
 pub fn function_name(ctx: &ExecutionContext) -> Vec<String> {
-    if ctx.datasource != DataSource::Payload {
+    if ctx.datasource != DataSource::Payload || ctx.flag_enabled(NO_GROUP_FLAG) {
         return vec![ctx.name.to_owned()];
     }
 
     match &ctx.next {
-        CloudFunction::None => (0..CONCURRENCY_8)
-            .map(|idx| format!("{}-{}", ctx.name, idx))
-            .collect(),
+        CloudFunction::None | CloudFunction::Sink(..) => {
+            group_members(&ctx.name, CONCURRENCY_8 as usize)
+        }
         CloudFunction::Chorus(..) => vec![ctx.name.to_owned()],
-        CloudFunction::Solo(..) => (0..CONCURRENCY_8)
-            .map(|idx| format!("{}-{}", ctx.name, idx))
-            .collect(),
+        CloudFunction::Solo(..) => group_members(&ctx.name, CONCURRENCY_8 as usize),
     }
 }
 
@@ -202,7 +304,7 @@ pub fn memory_size(_ctx: &ExecutionContext) -> Option<i64> {
 
 /// The Amazon Resource Name (ARN) of the function's execution role.
 pub async fn role() -> String {
-    let iam = IamClient::new(Region::default());
+    let iam = IamClient::new(config::aws_region());
     let resp = iam
         .get_role(GetRoleRequest {
             role_name: ROLE_NAME.to_owned(),
@@ -211,3 +313,185 @@ pub async fn role() -> String {
         .unwrap();
     resp.role.arn
 }
+
+/// The number of attempts of a throttled `ListFunctions` call.
+const LIST_FUNCTIONS_ATTEMPTS: u32 = 5;
+
+/// The members of a function group that are deployed and missing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupPresence {
+    /// The deployed members, in group index order.
+    pub present: Vec<String>,
+    /// The missing members, in group index order.
+    pub absent:  Vec<String>,
+}
+
+impl GroupPresence {
+    /// Sorts the first `group_size` members of the function group `name` by
+    /// whether they are among the `deployed` function names. Only the exact
+    /// member names count: `name-1` isn't present because of `name-10`.
+    pub fn new(name: &str, group_size: usize, deployed: &HashSet<String>) -> Self {
+        let (present, absent) = group_members(name, group_size)
+            .into_iter()
+            .partition(|member| deployed.contains(member));
+        Self { present, absent }
+    }
+}
+
+/// Checks which members of the function group `name` are deployed, in a single
+/// paginated sweep of `ListFunctions` rather than one `GetFunction` call per
+/// member.
+pub async fn group_presence(
+    client: &LambdaClient,
+    name: &str,
+    group_size: usize,
+) -> Result<GroupPresence> {
+    let deployed = list_function_names(client, &format!("{}-", name)).await?;
+    Ok(GroupPresence::new(name, group_size, &deployed))
+}
+
+/// Returns the names of all the deployed functions that start with `prefix`.
+///
+/// `ListFunctions` returns at most 50 functions per page, so the pages are
+/// followed until there is no next marker. A throttled page is retried with
+/// exponential backoff.
+pub async fn list_function_names(client: &LambdaClient, prefix: &str) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    let mut marker = None;
+    loop {
+        let mut attempt = 0;
+        let page = loop {
+            let request = ListFunctionsRequest {
+                marker: marker.clone(),
+                ..ListFunctionsRequest::default()
+            };
+            match client.list_functions(request).await {
+                Ok(page) => break page,
+                Err(RusotoError::Service(ListFunctionsError::TooManyRequests(_)))
+                    if attempt + 1 < LIST_FUNCTIONS_ATTEMPTS =>
+                {
+                    tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(SquirtleError::FunctionGeneration(e.to_string())),
+            }
+        };
+
+        names.extend(
+            page.functions
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|f| f.function_name)
+                .filter(|name| name.starts_with(prefix)),
+        );
+
+        marker = page.next_marker;
+        if marker.is_none() {
+            return Ok(names);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_presence_exact_names() {
+        let deployed: HashSet<String> = ["q-01-0", "q-01-2", "q-01-10", "q-01-1x", "q-02-1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            GroupPresence {
+                present: vec!["q-01-0".to_owned(), "q-01-2".to_owned()],
+                absent:  vec!["q-01-1".to_owned(), "q-01-3".to_owned()],
+            },
+            GroupPresence::new("q-01", 4, &deployed)
+        );
+    }
+
+    #[test]
+    fn skip_unchanged_functions() -> Result<()> {
+        let plan = r#"{"execution_plan":"coalesce_batches_exec","input":{"execution_plan":"memory_exec","schema":{"fields":[{"name":"c1","data_type":"Int64","nullable":true,"dict_id":0,"dict_is_ordered":false}],"metadata":{}},"projection":null},"target_batch_size":16384}"#;
+        let ctx = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            name: "SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836Z".to_owned(),
+            datasource: DataSource::Payload,
+            ..Default::default()
+        };
+        let deployed = environment(&ctx)?.unwrap().variables.unwrap();
+
+        assert_eq!(Deployment::Create, deployment(&ctx, None)?);
+        assert_eq!(Deployment::Unchanged, deployment(&ctx, Some(&deployed))?);
+
+        // The plan is unchanged, but the next function is different.
+        let rerouted = ExecutionContext {
+            next: CloudFunction::Solo("next".to_owned()),
+            ..ctx.clone()
+        };
+        assert_eq!(Deployment::Update, deployment(&rerouted, Some(&deployed))?);
+
+        // The plan is unchanged, but the shuffle keys are different.
+        let reshuffled = ExecutionContext {
+            shuffle_keys: vec!["a".to_owned()],
+            ..ctx.clone()
+        };
+        assert_eq!(
+            Deployment::Update,
+            deployment(&reshuffled, Some(&deployed))?
+        );
+
+        // The plan is changed.
+        let replanned = ExecutionContext {
+            plan: serde_json::from_str(&plan.replace("16384", "4096"))?,
+            ..ctx.clone()
+        };
+        assert_eq!(Deployment::Update, deployment(&replanned, Some(&deployed))?);
+
+        // The deployed function predates plan fingerprints.
+        let mut legacy = deployed.clone();
+        legacy.remove(PLAN_FINGERPRINT);
+        assert_eq!(Deployment::Update, deployment(&ctx, Some(&legacy))?);
+
+        // The deployed context is malformed.
+        let mut malformed = deployed.clone();
+        malformed.insert(globals["lambda"]["name"].to_owned(), "{}".to_owned());
+        assert_eq!(Deployment::Update, deployment(&ctx, Some(&malformed))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_tags() -> Result<()> {
+        assert_eq!(None, tags(&HashMap::new())?);
+
+        let mut map = HashMap::new();
+        map.insert("query".to_owned(), "q5".to_owned());
+        map.insert("run".to_owned(), "".to_owned());
+        assert_eq!(Some(map.clone()), tags(&map)?);
+
+        let mut invalid = map.clone();
+        invalid.insert("".to_owned(), "q5".to_owned());
+        assert!(tags(&invalid).is_err());
+
+        let mut invalid = map.clone();
+        invalid.insert("k".repeat(MAX_TAG_KEY_LEN + 1), "q5".to_owned());
+        assert!(tags(&invalid).is_err());
+
+        let mut invalid = map.clone();
+        invalid.insert("query".to_owned(), "v".repeat(MAX_TAG_VALUE_LEN + 1));
+        assert!(tags(&invalid).is_err());
+
+        let mut invalid = map.clone();
+        invalid.insert("aws:cloudformation".to_owned(), "q5".to_owned());
+        assert!(tags(&invalid).is_err());
+
+        let invalid = (0..=MAX_TAGS)
+            .map(|i| (i.to_string(), i.to_string()))
+            .collect::<HashMap<_, _>>();
+        assert!(tags(&invalid).is_err());
+
+        Ok(())
+    }
+}
@@ -19,11 +19,16 @@ use crate::funcgen::function::QueryFlow;
 
 use daggy::NodeIndex;
 use runtime::prelude::*;
-use rusoto_core::Region;
-use rusoto_lambda::{CreateFunctionRequest, Lambda, LambdaClient};
+use rusoto_core::RusotoError;
+use rusoto_lambda::{
+    CreateFunctionRequest, GetFunctionError, GetFunctionRequest, Lambda, LambdaClient,
+    TagResourceRequest, UpdateFunctionConfigurationRequest,
+};
+use std::collections::HashMap;
 use Schedule::Seconds;
 use StreamWindow::TumblingWindow;
 
+pub mod cost;
 pub mod lambda;
 
 /// Query Execution Context decides to execute your queries either remotely or
@@ -52,11 +57,21 @@ impl ExecutionEnvironment {
 
     /// Deploy a query to cloud function services on a public cloud.
     pub async fn deploy(&self, query: &QueryFlow) -> Result<()> {
+        self.deploy_with_tags(query, &HashMap::new()).await
+    }
+
+    /// Deploy a query to cloud function services on a public cloud, and tag
+    /// every created function with `tags` for cost allocation.
+    pub async fn deploy_with_tags(
+        &self,
+        query: &QueryFlow,
+        tags: &HashMap<String, String>,
+    ) -> Result<()> {
         match &self {
             ExecutionEnvironment::Local => Err(SquirtleError::FunctionGeneration(
                 "Local execution doesn't require a deployment.".to_owned(),
             )),
-            ExecutionEnvironment::Lambda => Self::lambda_deployment(&query).await,
+            ExecutionEnvironment::Lambda => Self::lambda_deployment(&query, tags).await,
             _ => unimplemented!(),
         }
     }
@@ -69,26 +84,106 @@ impl ExecutionEnvironment {
     /// - The execution role grants the function permission to use AWS services,
     /// such as Amazon CloudWatch Logs for log streaming and AWS X-Ray for
     /// request tracing.
-    async fn lambda_deployment(flow: &QueryFlow) -> Result<()> {
-        let client = &LambdaClient::new(Region::default());
-        for (_, ctx) in flow.ctx.iter() {
-            let _: Vec<_> = lambda::function_name(&ctx)
-                .iter()
-                .map(|name| async move {
-                    client
+    async fn lambda_deployment(flow: &QueryFlow, tags: &HashMap<String, String>) -> Result<()> {
+        for ctx in flow.ctx.values() {
+            ctx.check_serializable()?;
+        }
+
+        let client = &LambdaClient::new(config::aws_region());
+        let tags = &lambda::tags(tags)?;
+        let names: Vec<_> = flow
+            .ctx
+            .values()
+            .flat_map(|ctx| {
+                lambda::function_name(&ctx)
+                    .into_iter()
+                    .map(move |name| (ctx, name))
+            })
+            .collect();
+        let tasks = names.iter().map(|(ctx, name)| async move {
+            // only a missing function is created; any other failure to look it
+            // up is reported rather than papered over by a create.
+            let (deployed, arn) = match client
+                .get_function(GetFunctionRequest {
+                    function_name: name.to_owned(),
+                    ..GetFunctionRequest::default()
+                })
+                .await
+            {
+                Ok(resp) => {
+                    let config = resp.configuration.unwrap_or_default();
+                    let vars = config
+                        .environment
+                        .and_then(|env| env.variables)
+                        .unwrap_or_default();
+                    (Some(vars), config.function_arn)
+                }
+                Err(RusotoError::Service(GetFunctionError::ResourceNotFound(_))) => (None, None),
+                Err(e) => return Err(SquirtleError::FunctionGeneration(e.to_string())),
+            };
+
+            match lambda::deployment(&ctx, deployed.as_ref())? {
+                lambda::Deployment::Create => {
+                    // a new function gets its tags on creation.
+                    return client
                         .create_function(CreateFunctionRequest {
                             code: lambda::function_code(),
-                            environment: lambda::environment(&ctx),
+                            environment: lambda::environment(&ctx)?,
                             function_name: name.to_owned(),
                             handler: lambda::handler(),
                             memory_size: lambda::memory_size(&ctx),
                             role: lambda::role().await,
                             runtime: lambda::runtime(),
+                            tags: tags.clone(),
                             ..CreateFunctionRequest::default()
                         })
                         .await
-                })
-                .collect();
+                        .map(|_| ())
+                        .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()));
+                }
+                lambda::Deployment::Update => {
+                    client
+                        .update_function_configuration(UpdateFunctionConfigurationRequest {
+                            environment: lambda::environment(&ctx)?,
+                            function_name: name.to_owned(),
+                            memory_size: lambda::memory_size(&ctx),
+                            ..UpdateFunctionConfigurationRequest::default()
+                        })
+                        .await
+                        .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()))?;
+                }
+                lambda::Deployment::Unchanged => {}
+            }
+
+            // an existing function is tagged separately, so that its cost is
+            // attributed to this deployment too.
+            if let (Some(tags), Some(resource)) = (tags, arn) {
+                client
+                    .tag_resource(TagResourceRequest {
+                        resource,
+                        tags: tags.clone(),
+                    })
+                    .await
+                    .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()))?;
+            }
+            Ok(())
+        });
+
+        // A failed function must fail the deployment, otherwise the missing
+        // function is only discovered at query time.
+        let results = futures::future::join_all(tasks).await;
+        let failures: Vec<_> = names
+            .iter()
+            .zip(results)
+            .filter_map(|((_, name), result)| result.err().map(|e| format!("{}: {}", name, e)))
+            .collect();
+        if !failures.is_empty() {
+            return Err(SquirtleError::FunctionGeneration(format!(
+                "Failed to deploy {} of {} functions. {}",
+                failures.len(),
+                names.len(),
+                failures.join("; ")
+            )));
         }
 
         // Event source mapping
@@ -150,9 +245,10 @@ impl ExecutionEnvironment {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::funcgen::function::LAMBDA_ENVIRONMENT_LIMIT;
     use cargo_toml::Manifest;
-    use rusoto_core::Region;
     use rusoto_iam::{GetRoleRequest, Iam, IamClient};
+    use rusoto_lambda::{DeleteFunctionRequest, GetFunctionConfigurationRequest};
 
     #[tokio::test]
     async fn version_check() -> Result<()> {
@@ -164,7 +260,7 @@ mod tests {
     #[tokio::test]
     #[ignore]
     async fn get_role() -> Result<()> {
-        let iam = IamClient::new(Region::default());
+        let iam = IamClient::new(config::aws_region());
         let resp = iam
             .get_role(GetRoleRequest {
                 role_name: "squirtle".to_owned(),
@@ -174,4 +270,74 @@ mod tests {
         println!("{}", resp.role.arn);
         Ok(())
     }
+
+    /// Deploys a function with a marshalled context and reads the context back
+    /// from the function configuration, to catch any truncation or escaping
+    /// of the environment variable on the way through AWS Lambda.
+    ///
+    /// Set `AWS_ENDPOINT_URL` to run it against LocalStack, which must hold
+    /// the deployment package and the `squirtle` role.
+    #[tokio::test]
+    #[ignore]
+    async fn context_roundtrip() -> Result<()> {
+        let plan = r#"{"execution_plan":"coalesce_batches_exec","input":{"execution_plan":"memory_exec","schema":{"fields":[{"name":"c1","data_type":"Int64","nullable":true,"dict_id":0,"dict_is_ordered":false},{"name":"c2","data_type":"Float64","nullable":true,"dict_id":0,"dict_is_ordered":false},{"name":"c3","data_type":"Utf8","nullable":true,"dict_id":0,"dict_is_ordered":false}],"metadata":{}},"projection":null},"target_batch_size":16384}"#;
+        let ctx = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            name: "squirtle-context-roundtrip".to_owned(),
+            next: CloudFunction::Solo("SX72HzqFz1Qij4bP-00".to_owned()),
+            datasource: DataSource::Payload,
+            ..Default::default()
+        };
+        let environment = lambda::environment(&ctx)?;
+        let size: usize = environment
+            .as_ref()
+            .and_then(|env| env.variables.as_ref())
+            .map(|vars| vars.iter().map(|(k, v)| k.len() + v.len()).sum())
+            .unwrap_or_default();
+        assert!(size <= LAMBDA_ENVIRONMENT_LIMIT);
+
+        let client = LambdaClient::new(config::aws_region());
+        client
+            .create_function(CreateFunctionRequest {
+                code: lambda::function_code(),
+                environment,
+                function_name: ctx.name.clone(),
+                handler: lambda::handler(),
+                role: lambda::role().await,
+                runtime: lambda::runtime(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()))?;
+
+        let deployed = client
+            .get_function_configuration(GetFunctionConfigurationRequest {
+                function_name: ctx.name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()));
+        client
+            .delete_function(DeleteFunctionRequest {
+                function_name: ctx.name.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| SquirtleError::FunctionGeneration(e.to_string()))?;
+
+        let vars = deployed?
+            .environment
+            .and_then(|env| env.variables)
+            .unwrap_or_default();
+        let marshalled = vars.get(&globals["lambda"]["name"]).ok_or_else(|| {
+            SquirtleError::FunctionGeneration("No execution context deployed.".to_owned())
+        })?;
+        assert_eq!(ctx, ExecutionContext::unmarshal(marshalled)?);
+        assert_eq!(
+            Some(&ctx.fingerprint()?),
+            vars.get(lambda::PLAN_FINGERPRINT)
+        );
+
+        Ok(())
+    }
 }
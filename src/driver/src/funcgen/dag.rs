@@ -74,6 +74,28 @@ impl Deref for DagNode {
     }
 }
 
+/// How a node of a [`QueryDag`] differs from the node with the same index in
+/// a previously-built DAG. See [`QueryDag::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeDiff {
+    /// The node only exists in the new DAG, so its function must be created.
+    Added(NodeIndex),
+    /// The node only exists in the old DAG, so its function can be deleted.
+    Removed(NodeIndex),
+    /// The node exists in both DAGs, but its function must be updated.
+    Changed {
+        /// The index of the node in both DAGs.
+        node:        NodeIndex,
+        /// The serialized subplan changed.
+        plan:        bool,
+        /// The function concurrency changed.
+        concurrency: bool,
+        /// The parents or children of the node changed, i.e., where the
+        /// results are routed to or where the inputs come from.
+        routing:     bool,
+    },
+}
+
 /// A simple directed acyclic graph representation of the physical plan of a
 /// query statement. This graph allows the traversal of subplans in a
 /// topological order.  It is also possible to query subplan or dependencies
@@ -182,6 +204,53 @@ impl QueryDag {
         children
     }
 
+    /// Compares the DAG with a previously-built one, such as the DAG of the
+    /// deployed query, node by node. The nodes are matched by index, since
+    /// the same plan always splits into the same DAG.
+    ///
+    /// Returns the added, removed and changed nodes, in the order of their
+    /// indices. Unchanged nodes are left out, so an empty diff means there is
+    /// nothing to redeploy.
+    pub fn diff(&self, other: &QueryDag) -> Vec<NodeDiff> {
+        let edges = |dag: &QueryDag, n: NodeIndex| {
+            let mut parents: Vec<_> = dag.parents(n).iter(&dag.dag).map(|(_, p)| p).collect();
+            let mut children: Vec<_> = dag.children(n).iter(&dag.dag).map(|(_, c)| c).collect();
+            parents.sort();
+            children.sort();
+            (parents, children)
+        };
+
+        (0..self.node_count().max(other.node_count()))
+            .map(NodeIndex::new)
+            .filter_map(|n| match (self.get_node(n), other.get_node(n)) {
+                (Some(_), None) => Some(NodeDiff::Added(n)),
+                (None, Some(_)) => Some(NodeDiff::Removed(n)),
+                (Some(new), Some(old)) => {
+                    let plan = match (
+                        serde_json::to_string(&new.plan),
+                        serde_json::to_string(&old.plan),
+                    ) {
+                        (Ok(new), Ok(old)) => new != old,
+                        _ => true,
+                    };
+                    let concurrency = new.concurrency != old.concurrency;
+                    let routing = edges(self, n) != edges(other, n);
+                    if plan || concurrency || routing {
+                        Some(NodeDiff::Changed {
+                            node: n,
+                            plan,
+                            concurrency,
+                            routing,
+                        })
+                    } else {
+                        None
+                    }
+                }
+                (None, None) => None,
+            })
+            .collect()
+    }
+
     /// Return the internal daggy.
     pub fn context(&mut self) -> &mut DagPlan {
         &mut self.dag
@@ -290,6 +359,7 @@ mod tests {
 
     use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
     use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::empty::EmptyExec;
     use datafusion::physical_plan::filter::FilterExec;
     use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
     use datafusion::physical_plan::memory::MemoryExec;
@@ -300,6 +370,67 @@ mod tests {
     use runtime::prelude::*;
     use std::sync::Arc;
 
+    #[test]
+    fn dag_diff() -> Result<()> {
+        let leaf = || -> Arc<dyn ExecutionPlan> {
+            Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())))
+        };
+        let coalesce =
+            |size| -> Arc<dyn ExecutionPlan> { Arc::new(CoalesceBatchesExec::new(leaf(), size)) };
+        let build = |size, concurrency| {
+            let mut dag = QueryDag::new();
+            let root = dag.add_node(DagNode {
+                plan:        coalesce(4096),
+                concurrency: CONCURRENCY_1,
+            });
+            dag.add_child(
+                root,
+                DagNode {
+                    plan: coalesce(size),
+                    concurrency,
+                },
+            );
+            dag
+        };
+
+        let deployed = build(4096, CONCURRENCY_8);
+        assert!(build(4096, CONCURRENCY_8).diff(&deployed).is_empty());
+
+        // One node differs in its plan and concurrency.
+        let changed = build(1024, CONCURRENCY_1);
+        assert_eq!(
+            vec![NodeDiff::Changed {
+                node:        NodeIndex::new(1),
+                plan:        true,
+                concurrency: true,
+                routing:     false,
+            }],
+            changed.diff(&deployed)
+        );
+
+        // A node is added, which also reroutes its parent.
+        let mut grown = build(4096, CONCURRENCY_8);
+        grown.add_child(NodeIndex::new(1), leaf().into());
+        assert_eq!(
+            vec![
+                NodeDiff::Changed {
+                    node:        NodeIndex::new(1),
+                    plan:        false,
+                    concurrency: false,
+                    routing:     true,
+                },
+                NodeDiff::Added(NodeIndex::new(2)),
+            ],
+            grown.diff(&deployed)
+        );
+        assert_eq!(
+            NodeDiff::Removed(NodeIndex::new(2)),
+            deployed.diff(&grown)[1]
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[ignore]
     async fn simple_query() -> Result<()> {
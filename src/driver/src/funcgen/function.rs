@@ -19,15 +19,22 @@
 extern crate daggy;
 use daggy::{NodeIndex, Walker};
 
-use crate::deploy::ExecutionEnvironment;
+use crate::deploy::cost::{self, CostEstimate, CostParams};
+use crate::deploy::{lambda, ExecutionEnvironment};
 use crate::funcgen::dag::*;
 use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use datafusion::datasource::MemTable;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::projection::ProjectionExec;
 use datafusion::physical_plan::ExecutionPlan;
 use runtime::prelude::*;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 
-use blake2::{Blake2b, Digest};
 use chrono::{DateTime, Utc};
 
 /// `QueryFlow` contains all the context information of the current query
@@ -46,6 +53,61 @@ pub struct QueryFlow {
     pub ctx:   HashMap<NodeIndex, ExecutionContext>,
 }
 
+/// The maximum total size of the environment variables of a lambda function.
+pub const LAMBDA_ENVIRONMENT_LIMIT: usize = 4096;
+
+/// A description of the execution context of a cloud function, as it will be
+/// deployed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextDescriptor {
+    /// The index of the subplan in the DAG.
+    pub node:      usize,
+    /// The name of the cloud function.
+    pub name:      String,
+    /// The size of the marshalled execution context in bytes.
+    pub size:      usize,
+    /// The encoding of the marshalled execution context. It is
+    /// `Encoding::None` if compression doesn't pay off.
+    pub encoding:  Encoding,
+    /// The next cloud function to invoke.
+    pub next:      CloudFunction,
+    /// Whether the marshalled execution context exceeds the size limit of the
+    /// environment variables, which fails the deployment.
+    pub oversized: bool,
+}
+
+/// A summary of the cloud functions deployed for a query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopologySummary {
+    /// The number of cloud functions deployed for each stage, i.e., each
+    /// subplan in the DAG. A function group counts all its members.
+    pub functions: Vec<usize>,
+}
+
+impl TopologySummary {
+    /// The number of stages of the query.
+    pub fn stage_count(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// The total number of cloud functions deployed for the query.
+    pub fn function_count(&self) -> usize {
+        self.functions.iter().sum()
+    }
+}
+
+impl std::fmt::Display for TopologySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} functions across {} stages {:?}",
+            self.function_count(),
+            self.stage_count(),
+            self.functions
+        )
+    }
+}
+
 impl QueryFlow {
     /// Create a new `QueryFlow` from a given query.
     pub fn new(
@@ -63,16 +125,78 @@ impl QueryFlow {
         QueryFlow::from(query)
     }
 
+    /// Plan a SQL query over the given tables and create a new `QueryFlow`
+    /// that is ready to deploy.
+    ///
+    /// ## Arguments
+    ///
+    /// * `sql` - The SQL query.
+    /// * `tables` - The name and schema of each table in the query. The first
+    ///   table is the streaming data source of the query.
+    /// * `datasource` - The data source that triggers the query.
+    /// * `sink` - The data sink the results are written to. If `None`, the
+    ///   results are discarded.
+    pub fn from_sql(
+        sql: &str,
+        tables: &[(&str, SchemaRef)],
+        datasource: DataSource,
+        sink: Option<DataSinkType>,
+    ) -> Result<QueryFlow> {
+        let schema = match tables.first() {
+            Some((_, schema)) => schema.clone(),
+            None => return Err(SquirtleError::Plan("No table in the query.".to_owned())),
+        };
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        for (name, schema) in tables {
+            let table = MemTable::try_new(
+                schema.clone(),
+                vec![vec![RecordBatch::new_empty(schema.clone())]],
+            )?;
+            ctx.register_table(*name, Arc::new(table))?;
+        }
+
+        let plan = physical_plan(&mut ctx, sql)?;
+        let mut flow = QueryFlow::new(sql, schema, datasource, plan);
+        if let Some(sink) = sink {
+            if let Some(root) = flow.ctx.get_mut(&NodeIndex::new(0)) {
+                root.next = CloudFunction::Sink(sink);
+            }
+        }
+        Ok(flow)
+    }
+
     /// Create a new `QueryFlow` from a given query.
     pub fn from(query: Box<dyn Query>) -> QueryFlow {
+        QueryFlow::with_clock(query, &SystemClock)
+    }
+
+    /// Create a new `QueryFlow` from a given query, reading the deployment
+    /// time in the function names from `clock`.
+    pub fn with_clock(query: Box<dyn Query>, clock: &dyn Clock) -> QueryFlow {
         let plan = query.plan();
 
         let mut dag = QueryDag::from(plan);
         QueryFlow::add_source(plan, &mut dag);
-        let ctx = QueryFlow::build_context(&*query, &mut dag);
+        let ctx = QueryFlow::build_context(&*query, &mut dag, clock);
         QueryFlow { query, dag, ctx }
     }
 
+    /// Create exactly one function per node of the DAG, without expanding any
+    /// node into a function group. Each function routes its results to the
+    /// next function directly, so the topology matches the DAG one to one,
+    /// which is easier to reason about when debugging a query.
+    pub fn without_groups(mut self) -> QueryFlow {
+        for ctx in self.ctx.values_mut() {
+            if let CloudFunction::Chorus((name, _)) = &ctx.next {
+                ctx.next = CloudFunction::Solo(name.clone());
+            }
+            ctx.flags
+                .insert(NO_GROUP_FLAG.to_owned(), "true".to_owned());
+        }
+        self
+    }
+
     /// Deploy the lambda functions and execution context for the query.
     ///
     /// ## Arguments
@@ -84,6 +208,58 @@ impl QueryFlow {
         env.deploy(&self).await
     }
 
+    /// Deploy the lambda functions and execution context for the query, and
+    /// tag every created function with `tags`.
+    pub async fn deploy_with_tags(
+        &self,
+        env: ExecutionEnvironment,
+        tags: &HashMap<String, String>,
+    ) -> Result<()> {
+        env.deploy_with_tags(&self, tags).await
+    }
+
+    /// Describe the execution context of each cloud function as it will be
+    /// deployed, ordered by the subplan index in the DAG. This is cheap
+    /// compared to a deployment, so it can be used to sanity-check the
+    /// contexts beforehand.
+    pub fn describe_contexts(&self) -> Result<Vec<ContextDescriptor>> {
+        let mut nodes: Vec<_> = self.ctx.keys().collect();
+        nodes.sort();
+        nodes
+            .into_iter()
+            .map(|node| {
+                let ctx = &self.ctx[node];
+                let env = ctx.to_cloud_environment(Encoding::Zstd)?;
+                let marshalled = serde_json::to_string(&env)?;
+                Ok(ContextDescriptor {
+                    node:      node.index(),
+                    name:      ctx.name.clone(),
+                    size:      marshalled.len(),
+                    encoding:  env.encoding,
+                    next:      ctx.next.clone(),
+                    oversized: marshalled.len() > LAMBDA_ENVIRONMENT_LIMIT,
+                })
+            })
+            .collect()
+    }
+
+    /// Summarize the cloud functions that the deployment creates for each
+    /// stage, before creating anything. The members of a function group are
+    /// counted the same way as they are deployed.
+    pub fn topology_summary(&self) -> TopologySummary {
+        TopologySummary {
+            functions: (0..self.dag.node_count())
+                .map(|i| lambda::function_name(&self.ctx[&NodeIndex::new(i)]).len())
+                .collect(),
+        }
+    }
+
+    /// Estimate the cost of running the query on AWS Lambda. See
+    /// [`cost::estimate`].
+    pub fn estimate_cost(&self, params: &CostParams) -> CostEstimate {
+        cost::estimate(self, params)
+    }
+
     /// Add a data source node into `QueryDag`.
     #[inline]
     fn add_source(plan: &Arc<dyn ExecutionPlan>, dag: &mut QueryDag) {
@@ -116,8 +292,46 @@ impl QueryFlow {
         node_idx: &NodeIndex,
         timestamp: &DateTime<Utc>,
     ) -> String {
-        let plan_index = format!("{:0>2}", node_idx.index());
-        format!("{}-{}-{:?}", query_code, plan_index, timestamp)
+        FunctionName::new(query_code, node_idx.index())
+            .with_timestamp(&format!("{:?}", timestamp))
+            .to_string()
+    }
+
+    /// Returns the group-by columns of the aggregation at the top of `plan`,
+    /// i.e., with only per-row operators (projections and filters) above it.
+    ///
+    /// Each member of a function group only sees the keys shuffled to it, so
+    /// an operator above the aggregation that spans all the groups, such as a
+    /// sort or a limit, would yield a partial result per member. Such a plan
+    /// isn't shuffled: it gets no column, and so does a plan that doesn't
+    /// aggregate or groups by an expression other than a column.
+    fn shuffle_keys(plan: &Arc<dyn ExecutionPlan>) -> Vec<String> {
+        let mut p = plan.clone();
+        loop {
+            if let Some(agg) = p.as_any().downcast_ref::<HashAggregateExec>() {
+                return agg
+                    .group_expr()
+                    .iter()
+                    .map(|(expr, _)| {
+                        expr.as_any()
+                            .downcast_ref::<Column>()
+                            .map(|c| c.name().to_owned())
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .unwrap_or_default();
+            }
+            let any = p.as_any();
+            if !(any.is::<ProjectionExec>()
+                || any.is::<FilterExec>()
+                || any.is::<CoalesceBatchesExec>())
+            {
+                return vec![];
+            }
+            p = match p.children().as_slice() {
+                [child] => child.clone(),
+                _ => return vec![],
+            };
+        }
     }
 
     /// Create a **unique** execution context for each subplan in the DAG.
@@ -129,10 +343,10 @@ impl QueryFlow {
     fn build_context(
         query: &dyn Query,
         dag: &mut QueryDag,
+        clock: &dyn Clock,
     ) -> HashMap<NodeIndex, ExecutionContext> {
-        let mut query_code = base64::encode(&Blake2b::digest(query.sql().as_bytes()));
-        query_code.truncate(16);
-        let timestamp = chrono::offset::Utc::now();
+        let query_code = query_code(query.sql());
+        let timestamp: DateTime<Utc> = clock.now().into();
 
         let mut ctx = HashMap::new();
         let root = NodeIndex::new(0);
@@ -157,19 +371,28 @@ impl QueryFlow {
         queue.push_back(root);
         while let Some(parent) = queue.pop_front() {
             for (_, node) in dag.children(parent).iter(&dag) {
+                let next = {
+                    let name = ctx.get(&parent).unwrap().name.clone();
+                    if dag.get_node(parent).unwrap().concurrency == 1 {
+                        CloudFunction::Chorus((name, CONCURRENCY_8))
+                    } else {
+                        CloudFunction::Solo(name)
+                    }
+                };
+                // the output to a function group is shuffled by the group-by
+                // columns of its aggregation.
+                let shuffle_keys = match next {
+                    CloudFunction::Chorus(..) => {
+                        QueryFlow::shuffle_keys(&dag.get_node(parent).unwrap().plan)
+                    }
+                    _ => vec![],
+                };
                 ctx.insert(
                     node,
                     ExecutionContext {
                         plan: dag.get_node(node).unwrap().plan.clone(),
                         name: QueryFlow::function_name(&query_code, &node, &timestamp),
-                        next: {
-                            let name = ctx.get(&parent).unwrap().name.clone();
-                            if dag.get_node(parent).unwrap().concurrency == 1 {
-                                CloudFunction::Chorus((name, CONCURRENCY_8))
-                            } else {
-                                CloudFunction::Solo(name)
-                            }
-                        },
+                        next,
                         datasource: {
                             if node.index() == ncount - 1 {
                                 (*query.datasource()).clone()
@@ -178,6 +401,7 @@ impl QueryFlow {
                             }
                         },
                         query_number: None,
+                        shuffle_keys,
                         ..Default::default()
                     },
                 );
@@ -203,6 +427,10 @@ mod tests {
     use blake2::{Blake2b, Digest};
 
     async fn init_query_flow(sql: &str) -> Result<QueryFlow> {
+        init_query_flow_with_clock(sql, &SystemClock).await
+    }
+
+    async fn init_query_flow_with_clock(sql: &str, clock: &dyn Clock) -> Result<QueryFlow> {
         let schema = Arc::new(Schema::new(vec![
             Field::new("a", DataType::Utf8, false),
             Field::new("b", DataType::Int32, false),
@@ -233,7 +461,7 @@ mod tests {
 
         let mut dag = QueryDag::from(&query.plan());
         QueryFlow::add_source(query.plan(), &mut dag);
-        let ctx = QueryFlow::build_context(&*query, &mut dag);
+        let ctx = QueryFlow::build_context(&*query, &mut dag, clock);
 
         Ok(QueryFlow { query, dag, ctx })
     }
@@ -307,6 +535,96 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn deterministic_function_names() -> Result<()> {
+        let sql = concat!("SELECT b FROM t ORDER BY b ASC LIMIT 3");
+        let clock = MockClock::default();
+
+        let first = init_query_flow_with_clock(&sql, &clock).await?;
+        let second = init_query_flow_with_clock(&sql, &clock).await?;
+        assert_eq!(function_name(&first, 0)?, function_name(&second, 0)?);
+        assert!(function_name(&first, 0)?.ends_with("-00-1970-01-01T00:00:00Z"));
+
+        clock.advance(std::time::Duration::from_secs(1));
+        let third = init_query_flow_with_clock(&sql, &clock).await?;
+        assert!(function_name(&third, 0)?.ends_with("-00-1970-01-01T00:00:01Z"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_contexts() -> Result<()> {
+        let sql = concat!("SELECT b FROM t ORDER BY b ASC LIMIT 3");
+        let flow = init_query_flow(&sql).await?;
+
+        let descriptors = flow.describe_contexts()?;
+        assert_eq!(flow.dag.node_count(), descriptors.len());
+        for (i, desc) in descriptors.iter().enumerate() {
+            assert_eq!(i, desc.node);
+            assert_eq!(function_name(&flow, i)?, desc.name);
+            assert_eq!(next_function(&flow, i)?, desc.next);
+            assert!(desc.size > 0);
+            assert!(!desc.oversized);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_flow_from_sql() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let sql = "SELECT b, COUNT(a) FROM t GROUP BY b";
+
+        let flow = QueryFlow::from_sql(
+            sql,
+            &[("t", schema.clone())],
+            DataSource::UnknownEvent,
+            Some(DataSinkType::Collect),
+        )?;
+        assert_eq!(3, flow.dag.node_count());
+        assert_eq!(
+            CloudFunction::Sink(DataSinkType::Collect),
+            next_function(&flow, 0)?
+        );
+
+        // Run the query locally: the data source function holds the whole plan.
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+            ],
+        )?;
+        let mut source = flow.ctx[&NodeIndex::new(flow.dag.node_count() - 1)].clone();
+        source.feed_one_source(&vec![vec![batch]])?;
+        let batches = source.execute().await?;
+        assert_eq!(3, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        assert!(QueryFlow::from_sql(sql, &[], DataSource::UnknownEvent, None).is_err());
+
+        // The final aggregation is a function group, the partial aggregation and
+        // the data source are a single function each.
+        let summary = flow.topology_summary();
+        assert_eq!(vec![CONCURRENCY_8 as usize, 1, 1], summary.functions);
+        assert_eq!(3, summary.stage_count());
+        assert_eq!(10, summary.function_count());
+        assert_eq!(
+            "10 functions across 3 stages [8, 1, 1]",
+            summary.to_string()
+        );
+
+        // Without groups, each stage is a single function routed directly.
+        let flow = flow.without_groups();
+        assert_eq!(vec![1, 1, 1], flow.topology_summary().functions);
+        assert!(matches!(next_function(&flow, 1)?, CloudFunction::Solo(..)));
+        assert!(matches!(next_function(&flow, 2)?, CloudFunction::Solo(..)));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn execute_context_with_agg() -> Result<()> {
         let sql = concat!("SELECT MIN(a), AVG(b) ", "FROM t ", "GROUP BY b");
@@ -330,6 +648,31 @@ mod tests {
             CloudFunction::Solo(..)
         ));
 
+        // The partial aggregation shuffles its output to the final aggregation
+        // by the group-by column.
+        let shuffle_keys = |idx| functions.ctx[&NodeIndex::new(idx)].shuffle_keys.clone();
+        assert_eq!(vec!["b".to_owned()], shuffle_keys(1));
+        assert!(shuffle_keys(0).is_empty());
+        assert!(shuffle_keys(2).is_empty());
+
+        // A sort and a limit above the final aggregation span all the groups,
+        // so the partial aggregation isn't shuffled by key.
+        let sql = "SELECT b, COUNT(a) AS c FROM t GROUP BY b ORDER BY c DESC LIMIT 3";
+        let flow = init_query_flow(sql).await?;
+        assert!(matches!(
+            next_function(&flow, 1)?,
+            CloudFunction::Chorus(..)
+        ));
+        assert!(flow.ctx[&NodeIndex::new(1)].shuffle_keys.is_empty());
+
+        // A filter above the final aggregation is applied row by row.
+        let sql = "SELECT b, COUNT(a) FROM t GROUP BY b HAVING COUNT(a) > 1";
+        let flow = init_query_flow(sql).await?;
+        assert_eq!(
+            vec!["b".to_owned()],
+            flow.ctx[&NodeIndex::new(1)].shuffle_keys
+        );
+
         let dag = &mut functions.dag;
         assert_eq!(3, dag.node_count());
         assert_eq!(2, dag.edge_count());
@@ -361,8 +704,9 @@ mod tests {
         // The hash of the SQL statement is used as the first 16 characters of the
         // function name.
         let hash = Blake2b::digest(b"SELECT b FROM t ORDER BY b ASC LIMIT 3");
-        let mut s1 = base64::encode(&hash);
+        let mut s1 = format!("{:x}", hash);
         s1.truncate(16);
+        assert_eq!(query_code("SELECT b FROM t ORDER BY b ASC LIMIT 3"), s1);
 
         // The sub-plan index in the dag is used as the second 2 characters of the
         // function name.
@@ -14,17 +14,13 @@
 
 //! The generic lambda function for sub-plan execution on AWS Lambda.
 
-use arrow::record_batch::RecordBatch;
 use aws_lambda_events::event::kafka::KafkaEvent;
 use aws_lambda_events::event::kinesis::KinesisEvent;
-use datafusion::physical_plan::Partitioning;
-use futures::executor::block_on;
+use aws_lambda_events::event::sqs::SqsEvent;
 use lambda_runtime::{handler_fn, Context};
-use log::warn;
-use rayon::prelude::*;
+use log::{info, warn};
 use runtime::prelude::*;
-use rusoto_core::Region;
-use rusoto_lambda::{InvokeAsyncRequest, Lambda, LambdaClient};
+use serde_json::json;
 use serde_json::Value;
 use std::cell::Cell;
 use std::sync::Once;
@@ -61,12 +57,15 @@ macro_rules! init_exec_context {
         unsafe {
             // Init query executor from the cloud evironment.
             let init_context = || match std::env::var(&globals["lambda"]["name"]) {
-                Ok(s) => {
-                    EXECUTION_CONTEXT = CloudFunctionContext::Lambda((
-                        Box::new(ExecutionContext::unmarshal(&s)),
-                        Arena::new(),
-                    ));
-                }
+                Ok(s) => match ExecutionContext::unmarshal(&s) {
+                    Ok(ctx) => {
+                        EXECUTION_CONTEXT =
+                            CloudFunctionContext::Lambda((Box::new(ctx), Arena::new()));
+                    }
+                    Err(e) => {
+                        panic!("Failed to unmarshal the execution context: {}", e);
+                    }
+                },
                 Err(_) => {
                     panic!("No execution context in the cloud environment.");
                 }
@@ -86,45 +85,11 @@ macro_rules! init_exec_context {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    logger::init()?;
     lambda_runtime::run(handler_fn(handler)).await?;
     Ok(())
 }
 
-/// Invoke functions in the next stage of the data flow.
-fn invoke_next_functions(ctx: &ExecutionContext, batches: &mut Vec<RecordBatch>) -> Result<()> {
-    // retrieve the next lambda function names
-    let next_func = LambdaExecutor::next_function(&ctx)?;
-
-    // create uuid builder to assign id to each payload
-    let uuid_builder = UuidBuilder::new(&ctx.name, batches.len());
-
-    let client = &LambdaClient::new(Region::default());
-    batches.into_par_iter().enumerate().for_each(|(i, batch)| {
-        // call the lambda function asynchronously until it succeeds.
-        loop {
-            let uuid = uuid_builder.get(i);
-            let request = InvokeAsyncRequest {
-                function_name: next_func.clone(),
-                invoke_args:   Payload::to_bytes(&batch, uuid, Encoding::default()),
-            };
-
-            if let Ok(reponse) = block_on(client.invoke_async(request)) {
-                if let Some(code) = reponse.status {
-                    // A success response (202 Accepted) indicates that the request
-                    // is queued for invocation.
-                    if code == 202 {
-                        break;
-                    } else {
-                        warn!("Unknown invoke error: {}, retry ... ", code);
-                    }
-                }
-            }
-        }
-    });
-
-    Ok(())
-}
-
 async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Value> {
     let batch = match &ctx.datasource {
         DataSource::KinesisEvent(_) => {
@@ -143,44 +108,40 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
             }
             batch
         }
+        DataSource::SqsEvent => {
+            let sqs_event: SqsEvent = serde_json::from_value(event)?;
+            let batch = sqs::to_batch(sqs_event)?;
+            if batch.is_empty() {
+                return Err(SquirtleError::Execution("No SQS input!".to_owned()));
+            }
+            batch
+        }
+        DataSource::NdJson(source) => {
+            // the event is a string of newline-delimited JSON events.
+            let ndjson = event.as_str().ok_or_else(|| {
+                SquirtleError::Execution("The JSON events must be a string!".to_owned())
+            })?;
+            let (batch, skipped) = source.to_batch(ndjson.as_bytes())?;
+            if skipped > 0 {
+                warn!("Skipped {} malformed JSON events.", skipped);
+            }
+            if batch.is_empty() {
+                return Err(SquirtleError::Execution("No JSON input!".to_owned()));
+            }
+            batch
+        }
         _ => unimplemented!(),
     };
+    ctx.watermark = ctx.event_time_watermark(&batch)?;
 
     match LambdaExecutor::choose_strategy(&ctx, &batch) {
         ExecutionStrategy::Centralized => {
             // feed data into the physical plan
-            let output_partitions = LambdaExecutor::coalesce_batches(
-                vec![batch],
-                globals["lambda"]["target_batch_size"]
-                    .parse::<usize>()
-                    .unwrap(),
-            )
-            .await?;
+            let output_partitions =
+                LambdaExecutor::coalesce_batches(vec![batch], ctx.target_batch_size()?).await?;
 
-            let num_batches = output_partitions[0].len();
-            let parallelism = globals["lambda"]["parallelism"].parse::<usize>().unwrap();
-
-            if num_batches > parallelism {
-                ctx.feed_one_source(
-                    &LambdaExecutor::repartition(
-                        output_partitions,
-                        Partitioning::RoundRobinBatch(parallelism),
-                    )
-                    .await?,
-                );
-            } else if num_batches > 1 {
-                ctx.feed_one_source(
-                    &LambdaExecutor::repartition(
-                        output_partitions,
-                        Partitioning::RoundRobinBatch(num_batches),
-                    )
-                    .await?,
-                );
-            } else {
-                // only one batch exists
-                assert!(num_batches == 1);
-                ctx.feed_one_source(&output_partitions);
-            }
+            ctx.feed_one_source_repartitioned(output_partitions, None)
+                .await?;
 
             // query execution
             let batches = ctx.execute().await?;
@@ -198,7 +159,8 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
             .await?;
             assert_eq!(1, batches.len());
 
-            invoke_next_functions(&ctx, &mut batches[0])?;
+            let stats = LambdaExecutor::invoke_next_functions(&ctx, &mut batches[0])?;
+            info!("Sent {}", stats);
             Ok(serde_json::to_value(&ctx.name)?)
         }
     }
@@ -209,14 +171,17 @@ async fn payload_handler(
     arena: &mut Arena,
     event: Value,
 ) -> Result<Value> {
+    let before = RETRY_BUDGET.stats();
     let input_partitions = {
         if match &ctx.next {
-            CloudFunction::None | CloudFunction::Solo(..) => true,
+            CloudFunction::None | CloudFunction::Sink(..) | CloudFunction::Solo(..) => true,
             CloudFunction::Chorus(..) => false,
         } {
             // ressemble lambda n to 1
             let (ready, uuid) = arena.reassemble(event);
             if ready {
+                // the window is as complete as its slowest input
+                ctx.watermark = arena.watermark(&uuid.tid);
                 arena.batches(uuid.tid)
             } else {
                 return Err(SquirtleError::Execution(
@@ -225,6 +190,7 @@ async fn payload_handler(
             }
         } else {
             // partition lambda 1 to n
+            ctx.watermark = Payload::watermark_of(&event);
             let (batch, _) = Payload::to_batch(event);
             vec![batch]
         }
@@ -237,43 +203,42 @@ async fn payload_handler(
     }
 
     // TODO(gangliao): repartition input batches to speedup the operations.
-    ctx.feed_one_source(&input_partitions);
-    let output_partitions = ctx.execute().await?;
-
-    if ctx.next != CloudFunction::None {
-        let mut batches = LambdaExecutor::coalesce_batches(
-            vec![output_partitions],
-            globals["lambda"]["payload_batch_size"]
-                .parse::<usize>()
-                .unwrap(),
-        )
-        .await?;
-        assert_eq!(1, batches.len());
-        // call the next stage of the dataflow graph.
-        invoke_next_functions(&ctx, &mut batches[0])?;
+    if ctx.debug || ctx.flag_enabled(VALIDATE_FLAG) {
+        ctx.feed_one_source_checked(&input_partitions).await?;
+    } else {
+        ctx.feed_one_source_owned(input_partitions)?;
     }
+    // route the results to the data sink or the next stage of the dataflow graph.
+    let summary = ctx.collect_into_sink(None).await?;
+    let retries = summary.retries.since(&before);
+    info!("Sent {}", summary.payload);
+    info!("Retry budget: {}", retries);
 
     // TODO(gangliao): sink results to other cloud services.
-    Ok(serde_json::to_value(&ctx.name)?)
+    Ok(json!({"name": &ctx.name, "retries": retries.retries, "backoffs": retries.exhausted}))
 }
 
 async fn handler(event: Value, _: Context) -> Result<Value> {
     let (mut ctx, mut arena) = init_exec_context!();
+    info!("{}", ctx.plan_summary());
 
-    match &ctx.datasource {
+    let result = match &ctx.datasource {
         DataSource::Payload => payload_handler(&mut ctx, &mut arena, event).await,
-        DataSource::KinesisEvent(_) | DataSource::KafkaEvent(_) => {
-            source_handler(&mut ctx, event).await
-        }
+        DataSource::KinesisEvent(_)
+        | DataSource::KafkaEvent(_)
+        | DataSource::SqsEvent
+        | DataSource::NdJson(_) => source_handler(&mut ctx, event).await,
         DataSource::Json => Ok(event),
         _ => unimplemented!(),
-    }
+    };
+    logger::flush();
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::physical_plan::{ExecutionPlan, Partitioning};
     use driver::QueryFlow;
     use serde_json::json;
     use std::sync::Arc;
@@ -298,7 +263,7 @@ mod tests {
             ..Default::default()
         };
 
-        let encoded = lambda_context.marshal(Encoding::default());
+        let encoded = lambda_context.marshal(Encoding::default())?;
 
         // Configures the cloud environment
         std::env::set_var(&globals["lambda"]["name"], encoded);
@@ -16,16 +16,12 @@
 use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::Partitioning;
-use futures::executor::block_on;
 use lambda_runtime::{handler_fn, Context};
 use lazy_static::lazy_static;
-use log::warn;
+use log::{debug, info};
 use nexmark::event::{Auction, Bid, Person};
-use nexmark::{NexMarkEvent, NexMarkSource};
-use rayon::prelude::*;
+use nexmark::{query_streams, NexMarkEvent, NexMarkSource, DEFAULT_BATCH_SIZE, EXECUTABLE_QUERIES};
 use runtime::prelude::*;
-use rusoto_core::Region;
-use rusoto_lambda::{InvokeAsyncRequest, Lambda, LambdaClient};
 use serde_json::json;
 use serde_json::Value;
 use std::cell::Cell;
@@ -75,12 +71,15 @@ macro_rules! init_exec_context {
         unsafe {
             // Init query executor from the cloud evironment.
             let init_context = || match std::env::var(&**CONTEXT_NAME) {
-                Ok(s) => {
-                    EXECUTION_CONTEXT = CloudFunctionContext::Lambda((
-                        Box::new(ExecutionContext::unmarshal(&s)),
-                        Arena::new(),
-                    ));
-                }
+                Ok(s) => match ExecutionContext::unmarshal(&s) {
+                    Ok(ctx) => {
+                        EXECUTION_CONTEXT =
+                            CloudFunctionContext::Lambda((Box::new(ctx), Arena::new()));
+                    }
+                    Err(e) => {
+                        panic!("Failed to unmarshal the execution context: {}", e);
+                    }
+                },
                 Err(_) => {
                     panic!("No execution context in the cloud environment.");
                 }
@@ -98,54 +97,22 @@ macro_rules! init_exec_context {
     }};
 }
 
-/// Invoke functions in the next stage of the data flow.
-fn invoke_next_functions(ctx: &ExecutionContext, batches: &mut Vec<RecordBatch>) -> Result<()> {
-    // retrieve the next lambda function names
-    let next_func = LambdaExecutor::next_function(&ctx)?;
-
-    // create uuid builder to assign id to each payload
-    let uuid_builder = UuidBuilder::new(&ctx.name, batches.len());
-
-    let client = &LambdaClient::new(Region::default());
-    batches.into_par_iter().enumerate().for_each(|(i, batch)| {
-        // call the lambda function asynchronously until it succeeds.
-        loop {
-            let uuid = uuid_builder.get(i);
-            let request = InvokeAsyncRequest {
-                function_name: next_func.clone(),
-                invoke_args:   Payload::to_bytes(&batch, uuid, Encoding::default()),
-            };
-
-            if let Ok(reponse) = block_on(client.invoke_async(request)) {
-                if let Some(code) = reponse.status {
-                    // A success response (202 Accepted) indicates that the request
-                    // is queued for invocation.
-                    if code == 202 {
-                        break;
-                    } else {
-                        warn!("Unknown invoke error: {}, retry ... ", code);
-                    }
-                }
-            }
-        }
-    });
-
-    Ok(())
-}
-
 async fn payload_handler(
     ctx: &mut ExecutionContext,
     arena: &mut Arena,
     event: Value,
 ) -> Result<Value> {
+    let before = RETRY_BUDGET.stats();
     let input_partitions = {
         if match &ctx.next {
-            CloudFunction::None | CloudFunction::Solo(..) => true,
+            CloudFunction::None | CloudFunction::Sink(..) | CloudFunction::Solo(..) => true,
             CloudFunction::Chorus(..) => false,
         } {
             // ressemble lambda n to 1
             let (ready, uuid) = arena.reassemble(event);
             if ready {
+                // the window is as complete as its slowest input
+                ctx.watermark = arena.watermark(&uuid.tid);
                 arena.batches(uuid.tid)
             } else {
                 return Err(SquirtleError::Execution(
@@ -154,6 +121,7 @@ async fn payload_handler(
             }
         } else {
             // partition lambda 1 to n
+            ctx.watermark = Payload::watermark_of(&event);
             let (batch, _) = Payload::to_batch(event);
             vec![batch]
         }
@@ -166,29 +134,36 @@ async fn payload_handler(
     }
 
     // TODO(gangliao): repartition input batches to speedup the operations.
-    ctx.feed_one_source(&input_partitions);
-    let output_partitions = ctx.execute().await?;
-
-    if ctx.next != CloudFunction::None {
-        let mut batches = LambdaExecutor::coalesce_batches(
-            vec![output_partitions],
-            globals["lambda"]["payload_batch_size"]
-                .parse::<usize>()
-                .unwrap(),
-        )
-        .await?;
-        assert_eq!(1, batches.len());
-        // call the next stage of the dataflow graph.
-        invoke_next_functions(&ctx, &mut batches[0])?;
+    if ctx.debug || ctx.flag_enabled(VALIDATE_FLAG) {
+        ctx.feed_one_source_checked(&input_partitions).await?;
+    } else {
+        ctx.feed_one_source_owned(input_partitions)?;
     }
+    // route the results to the data sink or the next stage of the dataflow graph.
+    let summary = ctx.collect_into_sink(None).await?;
+    let retries = summary.retries.since(&before);
+    info!("Sent {}", summary.payload);
+    info!("Retry budget: {}", retries);
 
     // TODO(gangliao): sink results to other cloud services.
-    Ok(serde_json::to_value(&ctx.name)?)
+    Ok(json!({"name": &ctx.name, "retries": retries.retries, "backoffs": retries.exhausted}))
 }
 
 async fn nexmark_bench_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Value> {
+    let before = RETRY_BUDGET.stats();
     let event: NexMarkEvent = serde_json::from_value(event)?;
     let (epoch, source) = (event.epoch, event.source);
+    // The event of a stream's source function holds that stream only, which
+    // must be the single stream the query reads.
+    if let Some(stream) = event.stream {
+        let streams = ctx.query_number.map(query_streams).unwrap_or_default();
+        if streams != vec![stream] {
+            return Err(SquirtleError::Execution(format!(
+                "The {} stream can't feed query {:?}, which reads {:?}.",
+                stream, ctx.query_number, streams
+            )));
+        }
+    }
     if let DataSource::NexMarkEvent(source) = &ctx.datasource {
         match source.window {
             StreamWindow::TumblingWindow(Schedule::Seconds(_sec)) => {
@@ -199,47 +174,49 @@ async fn nexmark_bench_handler(ctx: &mut ExecutionContext, event: Value) -> Resu
                 unimplemented!();
             }
             StreamWindow::None => {
-                // data sink -- /dev/null
-                collect(ctx, event).await?;
+                let output_partitions = collect(ctx, event).await?;
+                // data sink -- /dev/null, unless a sink is given.
+                if let Some(sink) = ctx.sink()? {
+                    sink.write(&ctx.name, &output_partitions)?;
+                }
             }
             _ => unimplemented!(),
         }
     }
 
-    Ok(json!({"name": &ctx.name, "epoch": epoch, "source": source}))
+    let retries = RETRY_BUDGET.stats().since(&before);
+    info!("Retry budget: {}", retries);
+
+    Ok(json!({
+        "name": &ctx.name,
+        "epoch": epoch,
+        "source": source,
+        "retries": retries.retries,
+        "backoffs": retries.exhausted,
+    }))
 }
 
 async fn handler(event: Value, _: Context) -> Result<Value> {
     let (mut ctx, mut arena) = init_exec_context!();
+    info!("{}", ctx.plan_summary());
 
-    match &ctx.datasource {
+    // A warm-up invocation only loads the execution context.
+    if event.get("warm") == Some(&Value::Bool(true)) {
+        logger::flush();
+        return Ok(json!({"name": &ctx.name, "warm": true}));
+    }
+
+    let result = match &ctx.datasource {
         DataSource::Payload => payload_handler(&mut ctx, &mut arena, event).await,
         DataSource::NexMarkEvent(_) => nexmark_bench_handler(&mut ctx, event).await,
         _ => unimplemented!(),
-    }
+    };
+    logger::flush();
+    result
 }
 
 async fn feed_one_source(ctx: &mut ExecutionContext, batches: Vec<RecordBatch>) -> Result<()> {
-    let num_batches = batches.len();
-    let parallelism = globals["lambda"]["parallelism"].parse::<usize>().unwrap();
-
-    if num_batches > parallelism {
-        ctx.feed_one_source(
-            &LambdaExecutor::repartition(vec![batches], Partitioning::RoundRobinBatch(parallelism))
-                .await?,
-        );
-    } else if num_batches > 1 {
-        ctx.feed_one_source(
-            &LambdaExecutor::repartition(vec![batches], Partitioning::RoundRobinBatch(num_batches))
-                .await?,
-        );
-    } else {
-        // only one batch exists
-        assert!(num_batches == 1);
-        ctx.feed_one_source(&vec![batches]);
-    }
-
-    Ok(())
+    ctx.feed_one_source_repartitioned(vec![batches], None).await
 }
 
 async fn feed_two_source(
@@ -274,8 +251,7 @@ async fn feed_two_source(
         LambdaExecutor::repartition(vec![right], Partitioning::RoundRobinBatch(n_right)).await?
     };
 
-    ctx.feed_two_source(&left, &right);
-    Ok(())
+    ctx.feed_two_source(&left, &right)
 }
 
 async fn collect(ctx: &mut ExecutionContext, event: NexMarkEvent) -> Result<Vec<RecordBatch>> {
@@ -283,22 +259,46 @@ async fn collect(ctx: &mut ExecutionContext, event: NexMarkEvent) -> Result<Vec<
         return Err(SquirtleError::Execution("No Nexmark input!".to_owned()));
     }
 
+    let batch_size = match &ctx.datasource {
+        DataSource::NexMarkEvent(source) => source.batch_size(),
+        _ => DEFAULT_BATCH_SIZE,
+    };
+    if ctx.debug || ctx.flag_enabled(VALIDATE_FLAG) {
+        ctx.verify_schema(&[
+            PERSON_SCHEMA.clone(),
+            AUCTION_SCHEMA.clone(),
+            BID_SCHEMA.clone(),
+        ])?;
+    }
+
+    let to_batch = |events: &[u8], schema: &SchemaRef| {
+        NexMarkSource::to_batch_with_size(events, schema.clone(), batch_size)
+    };
+
     match ctx.query_number {
         Some(0) | Some(1) | Some(2) => {
-            let bids = NexMarkSource::to_batch(&event.bids, BID_SCHEMA.clone());
+            let bids = to_batch(&event.bids, &BID_SCHEMA);
+            ctx.watermark = ctx.event_time_watermark(&bids)?;
             feed_one_source(ctx, bids).await?;
         }
         Some(3) => {
-            let persons = NexMarkSource::to_batch(&event.persons, PERSON_SCHEMA.clone());
-            let auctions = NexMarkSource::to_batch(&event.auctions, AUCTION_SCHEMA.clone());
+            let persons = to_batch(&event.persons, &PERSON_SCHEMA);
+            let auctions = to_batch(&event.auctions, &AUCTION_SCHEMA);
+            ctx.watermark = ctx.event_time_watermark(&auctions)?;
             feed_two_source(ctx, persons, auctions).await?;
         }
         Some(4) => {
-            let auctions = NexMarkSource::to_batch(&event.auctions, AUCTION_SCHEMA.clone());
-            let bids = NexMarkSource::to_batch(&event.bids, BID_SCHEMA.clone());
+            let auctions = to_batch(&event.auctions, &AUCTION_SCHEMA);
+            let bids = to_batch(&event.bids, &BID_SCHEMA);
+            ctx.watermark = ctx.event_time_watermark(&bids)?;
             feed_two_source(ctx, auctions, bids).await?;
         }
-        _ => unimplemented!(),
+        _ => {
+            return Err(SquirtleError::Execution(format!(
+                "Unsupported NEXMark query: {:?}. The executable queries are {:?}.",
+                ctx.query_number, EXECUTABLE_QUERIES
+            )));
+        }
     }
 
     // query execution
@@ -312,7 +312,7 @@ async fn collect(ctx: &mut ExecutionContext, event: NexMarkEvent) -> Result<Vec<
 
         unsafe {
             INVOCATION_COUNTER_PER_INSTANCE += 1;
-            println!("# invocations: {}", INVOCATION_COUNTER_PER_INSTANCE);
+            debug!("# invocations: {}", INVOCATION_COUNTER_PER_INSTANCE);
         }
     }
 
@@ -321,6 +321,7 @@ async fn collect(ctx: &mut ExecutionContext, event: NexMarkEvent) -> Result<Vec<
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    logger::init()?;
     lambda_runtime::run(handler_fn(handler)).await?;
     Ok(())
 }
@@ -33,12 +33,16 @@ pub struct Arena(DashMap<String, WindowSession>);
 pub struct WindowSession {
     /// The window size (# data fragments / payloads).
     /// Note: [size] == [Uuid.seq_len]
-    pub size:    usize,
+    pub size:      usize,
     /// Reassembled record batches.
-    pub batches: Vec<Vec<RecordBatch>>,
+    pub batches:   Vec<Vec<RecordBatch>>,
     /// Validity bitmap is to track which data fragments in the window have not
     /// been received yet.
-    pub bitmap:  Bitmap,
+    pub bitmap:    Bitmap,
+    /// The event-time watermark of the window: the minimum of the watermarks
+    /// of the fragments received so far, since the window is only as complete
+    /// as its slowest input. `None` if any fragment has no watermark.
+    pub watermark: Option<i64>,
 }
 
 impl WindowSession {
@@ -68,11 +72,17 @@ impl Arena {
         }
     }
 
+    /// Return the event-time watermark of the window, if any.
+    pub fn watermark(&self, tid: &str) -> Option<i64> {
+        (*self).get(tid).and_then(|window| window.watermark)
+    }
+
     /// Ressemble the payload to a specific window session.
     ///
     /// Return true, if the window data collection is complete,
     pub fn reassemble(&mut self, event: Value) -> (bool, Uuid) {
         let mut ready = false;
+        let watermark = Payload::watermark_of(&event);
         let (fragment, uuid) = Payload::to_batch(event);
         match &mut (*self).get_mut(&uuid.tid) {
             Some(window) => {
@@ -80,14 +90,16 @@ impl Arena {
                 if !window.bitmap.is_set(uuid.seq_num) {
                     window.batches.push(fragment);
                     window.bitmap.set(uuid.seq_num);
+                    window.watermark = window.watermark.zip(watermark).map(|(a, b)| a.min(b));
                     ready = window.size == window.batches.len();
                 }
             }
             None => {
                 let mut window = WindowSession {
-                    size:    uuid.seq_len,
+                    size: uuid.seq_len,
                     batches: vec![fragment],
-                    bitmap:  Bitmap::new(uuid.seq_len),
+                    bitmap: Bitmap::new(uuid.seq_len),
+                    watermark,
                 };
 
                 ready = window.size == 1;
@@ -177,11 +189,45 @@ mod tests {
             (0..8).for_each(|i| assert_eq!(true, window.bitmap.is_set(i)));
         }
 
+        assert_eq!(None, arena.watermark(&tid));
         assert_eq!(8, arena.batches(tid).len());
         assert_eq!(0, arena.batches("no exists".to_string()).len());
 
         Ok(())
     }
+
+    #[test]
+    fn watermark_fan_in() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new("SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836", 3);
+        let payload = |i: usize, watermark: Option<i64>| -> Result<Value> {
+            let (bytes, _) = Payload::to_bytes_with_stats(
+                &batches[i],
+                uuids.get(i),
+                Encoding::default(),
+                watermark,
+            );
+            Ok(serde_json::from_slice(&bytes)?)
+        };
+        let tid = uuids.get(0).tid;
+
+        // The watermark of the window is the minimum of the fragments.
+        let mut arena = Arena::new();
+        arena.reassemble(payload(0, Some(300))?);
+        assert_eq!(Some(300), arena.watermark(&tid));
+        arena.reassemble(payload(1, Some(100))?);
+        arena.reassemble(payload(2, Some(200))?);
+        assert_eq!(Some(100), arena.watermark(&tid));
+
+        // A fragment without a watermark holds the window back.
+        let mut arena = Arena::new();
+        arena.reassemble(payload(0, Some(300))?);
+        arena.reassemble(payload(1, None)?);
+        arena.reassemble(payload(2, Some(200))?);
+        assert_eq!(None, arena.watermark(&tid));
+
+        Ok(())
+    }
 }
 
 pub mod bitmap;
@@ -16,12 +16,35 @@
 
 use ini::Ini;
 use lazy_static::lazy_static;
+use rusoto_core::Region;
 
 lazy_static! {
     /// Global settings.
     pub static ref GLOBALS: Ini = Ini::load_from_str(include_str!("config/squirtle.toml")).unwrap();
 }
 
+/// Returns the region that all AWS clients are constructed with.
+///
+/// If the environment variable named by `endpoint` in the `[aws]` section of
+/// `squirtle.toml` is set, the clients connect to that endpoint instead, so
+/// tests can run against LocalStack.
+pub fn aws_region() -> Region {
+    region_with_endpoint(std::env::var(&GLOBALS["aws"]["endpoint"]).ok().as_deref())
+}
+
+/// Returns the default region, or a custom region at `endpoint` if it is given
+/// and not empty. The region name still comes from `AWS_DEFAULT_REGION` or
+/// `AWS_REGION`, since request signing depends on it.
+pub fn region_with_endpoint(endpoint: Option<&str>) -> Region {
+    match endpoint.map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        Some(endpoint) => Region::Custom {
+            name:     Region::default().name().to_owned(),
+            endpoint: endpoint.to_owned(),
+        },
+        None => Region::default(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +80,20 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn endpoint_override() {
+        assert_eq!(Region::default(), region_with_endpoint(None));
+        assert_eq!(Region::default(), region_with_endpoint(Some(" ")));
+
+        let region = region_with_endpoint(Some("http://localhost:4566"));
+        assert_eq!(Region::default().name(), region.name());
+        assert_eq!(
+            Region::Custom {
+                name:     Region::default().name().to_owned(),
+                endpoint: "http://localhost:4566".to_owned(),
+            },
+            region
+        );
+    }
 }
@@ -15,33 +15,160 @@
 //! When the lambda function is called for the first time, it deserializes the
 //! corresponding execution context from the cloud environment variable.
 
+use super::datasink::{DataSinkType, OnError, SinkSummary};
 use super::datasource::DataSource;
 use super::encoding::Encoding;
+use crate::config::GLOBALS as globals;
 use crate::error::{Result, SquirtleError};
-use arrow::datatypes::{Schema, SchemaRef};
+use crate::executor::hash::{split_null_keys, ShuffleHash, Unroutable};
+use crate::executor::retry::RETRY_BUDGET;
+use crate::executor::{Executor, LambdaExecutor};
+use crate::naming::group_members;
+use arrow::array::Int64Array;
+use arrow::compute::{cast, max};
+use arrow::datatypes::{DataType, Schema, SchemaRef};
+use arrow::error::Result as ArrowResult;
+use arrow::ipc::reader::StreamReader;
 use arrow::record_batch::RecordBatch;
+use blake2::{Blake2b, Digest};
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
 use datafusion::physical_plan::collect;
 use datafusion::physical_plan::empty::EmptyExec;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::hash_join::HashJoinExec;
 use datafusion::physical_plan::memory::MemoryExec;
-use datafusion::physical_plan::ExecutionPlan;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+use futures::stream::StreamExt;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::sync::Arc;
 
 type CloudFunctionName = String;
 type GroupSize = u8;
 
+/// Serialized contexts smaller than this many bytes are not compressed, since
+/// the framing overhead of the compressors outweighs the savings.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// The version of the runtime that marshals the contexts.
+pub const RUNTIME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 /// Cloud environment context is a wrapper to support compression and
 /// serialization.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct CloudEnvironment {
     /// Lambda execution context.
-    /// `context` is the serialized version of `ExecutionContext`.
-    #[serde(with = "serde_bytes")]
-    pub context:  Vec<u8>,
+    /// `context` is the serialized version of `ExecutionContext`. It is
+    /// encoded in base64 rather than as a JSON array of numbers, which takes
+    /// up to 4 characters per byte of compressed data.
+    #[serde(with = "base64_bytes")]
+    pub context:        Vec<u8>,
     /// Compress `ExecutionContext` to guarantee the total size
     /// of all environment variables doesn't exceed 4 KB.
-    pub encoding: Encoding,
+    pub encoding:       Encoding,
+    /// The Zstd long-distance matching window (log2 of bytes) used by the
+    /// compressor. `None` means LDM is disabled. The decompressor must use the
+    /// same window, so it is carried along with the context.
+    #[serde(default)]
+    pub ldm_window_log: Option<u32>,
+    /// The version of the runtime that marshalled the context, to tell a
+    /// version skew between the client and the function from a malformed
+    /// context. `None` for contexts marshalled before the version was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version:        Option<String>,
+}
+
+impl CloudEnvironment {
+    /// Compresses the serialized context with `encoding`. The context is left
+    /// uncompressed, and recorded as `Encoding::None`, if it is smaller than
+    /// [`COMPRESSION_THRESHOLD`] or if compression doesn't shrink it.
+    fn compress(context: Vec<u8>, encoding: Encoding, ldm_window_log: Option<u32>) -> Self {
+        if context.len() >= COMPRESSION_THRESHOLD {
+            let compressed = match ldm_window_log {
+                Some(window_log) => encoding.compress_ldm(&context, window_log),
+                None => encoding.compress(&context),
+            };
+            if compressed.len() < context.len() {
+                return Self {
+                    context: compressed,
+                    encoding,
+                    ldm_window_log,
+                    version: Some(RUNTIME_VERSION.to_owned()),
+                };
+            }
+        }
+
+        Self {
+            context,
+            encoding: Encoding::None,
+            ldm_window_log: None,
+            version: Some(RUNTIME_VERSION.to_owned()),
+        }
+    }
+
+    /// Checks that the context was marshalled by a compatible runtime.
+    ///
+    /// The serialized layout may change between versions with a different
+    /// major version, or a different minor version before 1.0, following
+    /// semantic versioning. Such a version skew is an error. Other version
+    /// differences are only logged, and contexts without a version are
+    /// accepted as they are.
+    pub fn check_version(&self) -> Result<()> {
+        let version = match &self.version {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        if version == RUNTIME_VERSION {
+            return Ok(());
+        }
+
+        // The components of the version that break compatibility.
+        let breaking = |version: &str| {
+            let mut parts = version.split('.');
+            match parts.next() {
+                Some("0") => format!("0.{}", parts.next().unwrap_or_default()),
+                major => major.unwrap_or_default().to_owned(),
+            }
+        };
+        if breaking(version) != breaking(RUNTIME_VERSION) {
+            return Err(SquirtleError::Internal(format!(
+                "The context was marshalled by runtime {}, which is incompatible with runtime {}.",
+                version, RUNTIME_VERSION
+            )));
+        }
+        warn!(
+            "The context was marshalled by runtime {}, but is unmarshalled by runtime {}.",
+            version, RUNTIME_VERSION
+        );
+        Ok(())
+    }
+}
+
+/// Serializes bytes as a base64 string. Envelopes serialized as a JSON array
+/// of numbers by earlier versions can still be deserialized.
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bytes {
+        Base64(String),
+        Array(serde_bytes::ByteBuf),
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Bytes::deserialize(deserializer)? {
+            Bytes::Base64(s) => base64::decode(&s).map_err(serde::de::Error::custom),
+            Bytes::Array(bytes) => Ok(bytes.into_vec()),
+        }
+    }
 }
 
 /// Next lambda function call.
@@ -71,6 +198,9 @@ pub enum CloudFunction {
     /// There is no subsequent call to the cloud function at the end.
     /// TODO(gangliao): This function must include data sink operation.
     None,
+    /// There is no subsequent call to the cloud function at the end, and the
+    /// results are written to the given data sink.
+    Sink(DataSinkType),
 }
 
 impl Default for CloudFunction {
@@ -79,7 +209,38 @@ impl Default for CloudFunction {
     }
 }
 
+/// A concise form of the next call, such as `→ q5-01` for a function,
+/// `→ group q5-02 ×8` for a function group and `⇒ sink: stdout` for a data
+/// sink.
+impl fmt::Display for CloudFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloudFunction::Solo(name) => write!(f, "→ {}", name),
+            CloudFunction::Chorus((name, size)) => write!(f, "→ group {} ×{}", name, size),
+            CloudFunction::None => write!(f, "⇒ none"),
+            CloudFunction::Sink(sink) => write!(f, "⇒ sink: {}", sink),
+        }
+    }
+}
+
+impl CloudFunction {
+    /// Returns the names of the cloud functions behind this call, i.e., all
+    /// members of a `Chorus` group in strict order of the group index.
+    pub fn member_names(&self) -> Vec<String> {
+        match self {
+            CloudFunction::Solo(name) => vec![name.to_owned()],
+            CloudFunction::Chorus((name, size)) => group_members(name, *size as usize),
+            CloudFunction::None | CloudFunction::Sink(..) => vec![],
+        }
+    }
+}
+
 /// Lambda execution context.
+///
+/// Cloning a context is cheap: the plan is an `Arc` shared by the clones, and
+/// only the small fields (names, data source, flags) are copied. A clone that
+/// needs a different plan replaces `plan` rather than mutating the shared one,
+/// which is how `feed_one_source` and friends rebuild the plan.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ExecutionContext {
     /// The physical sub-plan.
@@ -116,8 +277,53 @@ pub struct ExecutionContext {
     pub query_number: Option<usize>,
     /// Print the debug information in the lambda instance.
     pub debug:        bool,
+    /// The columns the results are hash-partitioned by before they are handed
+    /// off to the next function group, so that each member receives all the
+    /// rows of its keys. If empty, the results are not shuffled.
+    #[serde(default)]
+    pub shuffle_keys: Vec<String>,
+    /// Per-query feature flags that toggle optional behaviors of the function,
+    /// such as [`VALIDATE_FLAG`]. Empty flags are left out of the marshalled
+    /// context.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub flags:        HashMap<String, String>,
+    /// The event-time watermark (ms) of the data being processed, which is
+    /// passed on with the results to the next function. It is set per
+    /// invocation, so it is never marshalled.
+    #[serde(skip)]
+    pub watermark:    Option<i64>,
 }
 
+/// The feature flag that checks the fed batches against the plan, as the debug
+/// mode does.
+pub const VALIDATE_FLAG: &str = "validate";
+
+/// The feature flag that overrides the target batch size of the plan, so that
+/// the same plan fits in functions of different memory sizes.
+pub const BATCH_SIZE_FLAG: &str = "target_batch_size";
+
+/// The feature flag that deploys a single function per subplan instead of a
+/// function group, so the functions match the query DAG one to one.
+pub const NO_GROUP_FLAG: &str = "no_group";
+
+/// The feature flag that selects the [`ShuffleHash`] of the shuffle keys, such
+/// as `murmur2` to co-partition with a Kafka producer.
+pub const SHUFFLE_HASH_FLAG: &str = "shuffle_hash";
+
+/// The feature flag that selects the [`Unroutable`] policy of the shuffled rows
+/// that can't be routed to a member of the next function group, such as
+/// `dead_letter`.
+pub const UNROUTABLE_FLAG: &str = "unroutable";
+
+/// The feature flag that names the event-time column (in ms, e.g. a `Date64`)
+/// of the source, whose maximum in the batch is the watermark of the payloads.
+pub const EVENT_TIME_FLAG: &str = "event_time";
+
+/// The feature flag that selects the [`OnError`] policy of the results that
+/// the last function produced before its execution failed, such as
+/// `flush_remaining`.
+pub const ON_ERROR_FLAG: &str = "on_error";
+
 impl Default for ExecutionContext {
     fn default() -> ExecutionContext {
         ExecutionContext {
@@ -127,18 +333,45 @@ impl Default for ExecutionContext {
             datasource:   DataSource::default(),
             query_number: Some(0),
             debug:        false,
+            shuffle_keys: vec![],
+            flags:        HashMap::new(),
+            watermark:    None,
         }
     }
 }
 
+/// The plans are compared by their serialized form. A plan that can't be
+/// serialized, e.g. because it contains an operator without serde support, is
+/// never equal to any other plan, itself included.
 impl PartialEq for ExecutionContext {
     fn eq(&self, other: &ExecutionContext) -> bool {
         self.name == other.name
             && self.next == other.next
             && self.datasource == other.datasource
             && self.query_number == other.query_number
-            && serde_json::to_string(&self.plan).unwrap()
-                == serde_json::to_string(&other.plan).unwrap()
+            && self.shuffle_keys == other.shuffle_keys
+            && self.flags == other.flags
+            && match (
+                serde_json::to_string(&self.plan),
+                serde_json::to_string(&other.plan),
+            ) {
+                (Ok(lhs), Ok(rhs)) => lhs == rhs,
+                _ => false,
+            }
+    }
+}
+
+/// A one-line summary of the context, e.g. `q5-00 (4 nodes) → group q5-01 ×8`,
+/// instead of the whole plan dumped by `Debug`.
+impl fmt::Display for ExecutionContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} nodes) {}",
+            self.name,
+            self.num_nodes(),
+            self.next
+        )
     }
 }
 
@@ -148,109 +381,895 @@ impl ExecutionContext {
         &mut self.plan
     }
 
+    /// Returns the value of the feature flag `name`, if it is set.
+    pub fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(|v| v.as_str())
+    }
+
+    /// Returns true if the feature flag `name` is set to `true`.
+    pub fn flag_enabled(&self, name: &str) -> bool {
+        self.flag(name)
+            .and_then(|v| v.trim().parse::<bool>().ok())
+            .unwrap_or(false)
+    }
+
+    /// Returns the output schema of the execution plan, without executing it.
+    pub fn schema(&self) -> SchemaRef {
+        self.plan.schema()
+    }
+
     /// Executes the physical plan.
     /// `execute` must be called after the execution of `feed_one_source` or
     /// `feed_two_source`.
     pub async fn execute(&mut self) -> Result<Vec<RecordBatch>> {
         match collect(self.plan().clone()).await {
             Ok(b) => Ok(b),
-            Err(e) => Err(SquirtleError::Plan(format!(
-                "{}. Failed to execute the plan '{:?}'",
-                e, self.plan
-            ))),
+            Err(e) => Err(self.execution_error(e)),
         }
     }
 
+    /// Executes the physical plan one output partition after another, and
+    /// keeps the batches produced before the first error. Returns the batches
+    /// and the error, if any.
+    async fn execute_partially(&self) -> (Vec<RecordBatch>, Option<SquirtleError>) {
+        let mut batches = vec![];
+        for i in 0..self.plan.output_partitioning().partition_count() {
+            let mut stream = match self.plan.execute(i).await {
+                Ok(stream) => stream,
+                Err(e) => return (batches, Some(self.execution_error(e))),
+            };
+            while let Some(result) = stream.next().await {
+                match result {
+                    Ok(batch) => batches.push(batch),
+                    Err(e) => return (batches, Some(self.execution_error(e))),
+                }
+            }
+        }
+        (batches, None)
+    }
+
+    fn execution_error(&self, e: impl fmt::Display) -> SquirtleError {
+        SquirtleError::Plan(format!(
+            "{}. Failed to execute the plan '{:?}'",
+            e, self.plan
+        ))
+    }
+
     /// Serializes `ExecutionContext` from client-side.
-    pub fn marshal(&self, encoding: Encoding) -> String {
-        match encoding {
-            Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => {
-                let encoded: Vec<u8> = serde_json::to_vec(&self).unwrap();
-                serde_json::to_string(&CloudEnvironment {
-                    context: encoding.compress(&encoded),
-                    encoding,
-                })
-                .unwrap()
+    ///
+    /// Returns an error if the plan contains an operator that can't be
+    /// serialized.
+    pub fn marshal(&self, encoding: Encoding) -> Result<String> {
+        Ok(serde_json::to_string(
+            &self.to_cloud_environment(encoding)?,
+        )?)
+    }
+
+    /// Returns the cloud environment that [`ExecutionContext::marshal`]
+    /// serializes, so that the encoding actually used and the size of the
+    /// compressed context can be inspected without parsing the string back.
+    pub fn to_cloud_environment(&self, encoding: Encoding) -> Result<CloudEnvironment> {
+        let encoded: Vec<u8> = serde_json::to_vec(&self)?;
+        Ok(match encoding {
+            Encoding::Snappy | Encoding::Lz4 | Encoding::Zlib | Encoding::Gzip | Encoding::Zstd => {
+                CloudEnvironment::compress(encoded, encoding, None)
             }
-            Encoding::None => serde_json::to_string(&CloudEnvironment {
-                context: serde_json::to_vec(&self).unwrap(),
+            Encoding::None => CloudEnvironment {
+                context: encoded,
                 encoding,
-            })
-            .unwrap(),
-            _ => unimplemented!(),
-        }
+                ldm_window_log: None,
+                version: Some(RUNTIME_VERSION.to_owned()),
+            },
+        })
+    }
+
+    /// Serializes `ExecutionContext` from client-side using Zstd with
+    /// long-distance matching, which shrinks wide plans with repeated subtrees
+    /// (self-joins, repeated projections) better than the default window.
+    pub fn marshal_ldm(&self, window_log: u32) -> Result<String> {
+        let encoded: Vec<u8> = serde_json::to_vec(&self)?;
+        Ok(serde_json::to_string(&CloudEnvironment::compress(
+            encoded,
+            Encoding::Zstd,
+            Some(window_log),
+        ))?)
     }
 
     /// Deserializes `ExecutionContext` from cloud-side.
-    pub fn unmarshal(s: &str) -> ExecutionContext {
-        let env: CloudEnvironment = serde_json::from_str(s).unwrap();
+    ///
+    /// Returns an error if the context is malformed, its plan contains an
+    /// operator that can't be deserialized, or the plan is deeper than
+    /// `max_plan_depth` in `squirtle.toml`.
+    pub fn unmarshal(s: &str) -> Result<ExecutionContext> {
+        let env: CloudEnvironment = serde_json::from_str(s)?;
+        env.check_version()?;
+
+        let ctx: ExecutionContext = if let Some(window_log) = env.ldm_window_log {
+            let encoded = env.encoding.decompress_ldm(&env.context, window_log);
+            serde_json::from_slice(&encoded)?
+        } else {
+            match env.encoding {
+                Encoding::Snappy
+                | Encoding::Lz4
+                | Encoding::Zlib
+                | Encoding::Gzip
+                | Encoding::Zstd => {
+                    let encoded = env.encoding.decompress(&env.context);
+                    serde_json::from_slice(&encoded)?
+                }
+                Encoding::None => serde_json::from_slice(&env.context)?,
+            }
+        };
+
+        ctx.check_plan_depth(
+            globals["lambda"]["max_plan_depth"]
+                .parse::<usize>()
+                .unwrap(),
+        )?;
+
+        let mut ctx = ctx;
+        if ctx.flag(BATCH_SIZE_FLAG).is_some() {
+            ctx.set_target_batch_size(ctx.target_batch_size()?);
+        }
+        Ok(ctx)
+    }
+
+    /// Returns the target batch size of the function: the [`BATCH_SIZE_FLAG`]
+    /// flag if set, otherwise `target_batch_size` in `squirtle.toml`.
+    pub fn target_batch_size(&self) -> Result<usize> {
+        match self.flag(BATCH_SIZE_FLAG) {
+            Some(size) => match size.parse::<usize>() {
+                Ok(size) if size > 0 => Ok(size),
+                _ => Err(SquirtleError::Plan(format!(
+                    "Invalid {} flag of {}: {}",
+                    BATCH_SIZE_FLAG, self.name, size
+                ))),
+            },
+            None => Ok(globals["lambda"]["target_batch_size"]
+                .parse::<usize>()
+                .unwrap()),
+        }
+    }
+
+    /// Returns the hash function of the shuffle keys: the [`SHUFFLE_HASH_FLAG`]
+    /// flag if set, otherwise BLAKE2b.
+    pub fn shuffle_hash(&self) -> Result<ShuffleHash> {
+        self.flag(SHUFFLE_HASH_FLAG)
+            .map_or(Ok(ShuffleHash::default()), |hash| hash.parse())
+    }
+
+    /// Returns the policy of the shuffled rows that can't be routed: the
+    /// [`UNROUTABLE_FLAG`] flag if set, otherwise [`Unroutable::RouteToZero`].
+    pub fn unroutable(&self) -> Result<Unroutable> {
+        self.flag(UNROUTABLE_FLAG)
+            .map_or(Ok(Unroutable::default()), |policy| policy.parse())
+    }
+
+    /// Returns the policy of the results produced before an execution error:
+    /// the [`ON_ERROR_FLAG`] flag if set, otherwise [`OnError::Discard`].
+    pub fn on_error(&self) -> Result<OnError> {
+        self.flag(ON_ERROR_FLAG)
+            .map_or(Ok(OnError::default()), |policy| policy.parse())
+    }
+
+    /// Returns the watermark of the source batches: the maximum of the
+    /// [`EVENT_TIME_FLAG`] column, or `None` if the flag is unset or the
+    /// batches are empty.
+    pub fn event_time_watermark(&self, batches: &[RecordBatch]) -> Result<Option<i64>> {
+        let column = match self.flag(EVENT_TIME_FLAG) {
+            Some(column) => column,
+            None => return Ok(None),
+        };
+        let mut watermark = None;
+        for batch in batches {
+            let times = cast(
+                batch.column(batch.schema().index_of(column)?),
+                &DataType::Int64,
+            )?;
+            let times = times.as_any().downcast_ref::<Int64Array>().unwrap();
+            watermark = watermark.max(max(times));
+        }
+        Ok(watermark)
+    }
+
+    /// Sets the target batch size of every `CoalesceBatchesExec` in the plan,
+    /// without re-planning the query.
+    pub fn set_target_batch_size(&mut self, size: usize) {
+        self.plan = rewrite_nodes(&self.plan, &mut |p| {
+            p.as_any().downcast_ref::<CoalesceBatchesExec>().map(
+                |coalesce| -> Arc<dyn ExecutionPlan> {
+                    Arc::new(CoalesceBatchesExec::new(
+                        coalesce.children()[0].clone(),
+                        size,
+                    ))
+                },
+            )
+        });
+    }
 
-        match env.encoding {
-            Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => {
-                let encoded = env.encoding.decompress(&env.context);
-                serde_json::from_slice(&encoded).unwrap()
+    /// Returns the data sink of the last function in the dataflow, or `None`
+    /// if the function is not the last one or its results are discarded.
+    ///
+    /// The sink named by the environment variable configured as `sink` in
+    /// `squirtle.toml` is read at invocation time and overrides the sink in
+    /// the context, so the sink can be changed without a redeployment.
+    pub fn sink(&self) -> Result<Option<DataSinkType>> {
+        let sink = match &self.next {
+            CloudFunction::Sink(sink) => Some(sink),
+            CloudFunction::None => None,
+            CloudFunction::Solo(..) | CloudFunction::Chorus(..) => return Ok(None),
+        };
+        let name = std::env::var(&globals["lambda"]["sink"]).ok();
+        DataSinkType::overridden(name.as_deref(), sink)
+    }
+
+    /// Writes the shuffled rows that can't be routed to the dead-letter sink,
+    /// `dead_letter_sink` in `squirtle.toml`, under the key
+    /// `<function name>-dead-letter`.
+    pub fn write_dead_letters(&self, batches: &[RecordBatch]) -> Result<()> {
+        if batches.is_empty() {
+            return Ok(());
+        }
+        let sink: DataSinkType = globals["lambda"]["dead_letter_sink"].parse()?;
+        sink.write_results(&format!("{}-dead-letter", self.name), batches, false)
+    }
+
+    /// Executes the plan and routes the results to `self.next`: the results
+    /// are either written to the data sink, forwarded to the next function in
+    /// the dataflow, or discarded if there is no subsequent call.
+    ///
+    /// `key` is the key the results are recorded under in the data sink. It
+    /// defaults to the function name.
+    ///
+    /// If the execution fails, the results produced so far are written to the
+    /// data sink before the error is returned, or dropped, according to
+    /// [`ExecutionContext::on_error`]. Results that go to the next function
+    /// are always dropped.
+    pub async fn collect_into_sink(&mut self, key: Option<&[u8]>) -> Result<SinkSummary> {
+        let key = match key {
+            Some(key) => String::from_utf8_lossy(key).into_owned(),
+            None => self.name.clone(),
+        };
+        let flush_on_error = match &self.next {
+            CloudFunction::None | CloudFunction::Sink(..) => {
+                self.on_error()? == OnError::FlushRemaining
+            }
+            CloudFunction::Solo(..) | CloudFunction::Chorus(..) => false,
+        };
+        let batches = if flush_on_error {
+            let (batches, error) = self.execute_partially().await;
+            if let Some(e) = error {
+                // a failed flush is only logged, so that the execution error
+                // is what the caller gets.
+                let flushed = self.sink().and_then(|sink| match sink {
+                    Some(sink) => sink.write(&key, &batches),
+                    None => Ok(()),
+                });
+                if let Err(flush) = flushed {
+                    warn!("Failed to flush the results of {} on error: {}", key, flush);
+                }
+                return Err(e);
+            }
+            batches
+        } else {
+            self.execute().await?
+        };
+        let mut summary = SinkSummary::new(&batches, &self.next);
+
+        match &self.next {
+            CloudFunction::None | CloudFunction::Sink(..) => {
+                if let Some(sink) = self.sink()? {
+                    sink.write(&key, &batches)?;
+                }
+            }
+            CloudFunction::Chorus((_, group_size)) if !self.shuffle_keys.is_empty() => {
+                let (batches, null_keys) = split_null_keys(batches, &self.shuffle_keys)?;
+                let mut buckets = LambdaExecutor::shuffle(
+                    batches,
+                    &self.shuffle_keys,
+                    *group_size as usize,
+                    self.shuffle_hash()?,
+                )
+                .await?;
+                let dead_letters = self.unroutable()?.reroute(
+                    null_keys,
+                    &mut buckets,
+                    "the shuffle keys are null",
+                )?;
+                self.write_dead_letters(&dead_letters)?;
+                for bucket in buckets.iter_mut().filter(|b| !b.is_empty()) {
+                    *bucket = LambdaExecutor::coalesce_batches(
+                        vec![std::mem::take(bucket)],
+                        globals["lambda"]["payload_batch_size"]
+                            .parse::<usize>()
+                            .unwrap(),
+                    )
+                    .await?
+                    .remove(0);
+                }
+                // call each member of the next function group with its bucket.
+                summary.payload = LambdaExecutor::invoke_group_members(self, &mut buckets)?;
+            }
+            CloudFunction::Solo(..) | CloudFunction::Chorus(..) => {
+                if !batches.is_empty() {
+                    let mut batches = LambdaExecutor::coalesce_batches(
+                        vec![batches],
+                        globals["lambda"]["payload_batch_size"]
+                            .parse::<usize>()
+                            .unwrap(),
+                    )
+                    .await?;
+                    assert_eq!(1, batches.len());
+                    // call the next stage of the dataflow graph.
+                    summary.payload = LambdaExecutor::invoke_next_functions(self, &mut batches[0])?;
+                }
             }
-            Encoding::None => serde_json::from_slice(&env.context).unwrap(),
-            _ => unimplemented!(),
         }
+
+        summary.retries = RETRY_BUDGET.stats();
+        Ok(summary)
+    }
+
+    /// Returns the fingerprint of the execution plan, i.e., the truncated
+    /// blake2 hash of the serialized plan. Contexts with the same plan have the
+    /// same fingerprint, even if their other fields (such as `name` and
+    /// `next`) differ.
+    pub fn fingerprint(&self) -> Result<String> {
+        let plan = serde_json::to_vec(&self.plan)?;
+        let mut fingerprint = base64::encode(&Blake2b::digest(&plan));
+        fingerprint.truncate(16);
+        Ok(fingerprint)
     }
 
     /// Feed one data source to the execution plan.
-    pub fn feed_one_source(&mut self, partitions: &Vec<Vec<RecordBatch>>) {
+    ///
+    /// The outer vector holds the partitions and the inner vectors their
+    /// batches. See [`ExecutionContext::feed_partitions`].
+    pub fn feed_one_source(&mut self, partitions: &Vec<Vec<RecordBatch>>) -> Result<()> {
+        self.feed_partitions(partitions.clone())
+    }
+
+    /// Feeds the batches to the execution plan as a single partition: the
+    /// leaf `MemoryExec` has one partition, which yields the batches in order.
+    pub fn feed_single_partition(&mut self, batches: Vec<RecordBatch>) -> Result<()> {
+        self.feed_partitions(vec![batches])
+    }
+
+    /// Feeds the partitions to the execution plan: `partitions[i]` becomes
+    /// partition `i` of the leaf `MemoryExec`, which yields the batches of
+    /// `partitions[i]` in order. The leaf thus has `partitions.len()` output
+    /// partitions, which the operators above it execute in parallel.
+    ///
+    /// The first leaf of the plan in breadth-first order receives the
+    /// partitions. The plan is rebuilt with a new `MemoryExec` in place of
+    /// that leaf, so that no node shared with another `Arc` is mutated.
+    ///
+    /// Returns an error if the partitions can't be turned into a `MemoryExec`
+    /// for that leaf, e.g., because their schema differs from the leaf's.
+    pub fn feed_partitions(&mut self, partitions: Vec<Vec<RecordBatch>>) -> Result<()> {
+        let target = self.first_leaf().map(|p| Arc::as_ptr(&p) as *const u8);
+        self.plan = feed_leaves(&self.plan, &mut |leaf| {
+            if Some(Arc::as_ptr(leaf) as *const u8) == target {
+                Ok(Some(memory_exec(leaf, &partitions)?))
+            } else {
+                Ok(None)
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Feed one data source to the execution plan, taking ownership of the
+    /// partitions.
+    ///
+    /// Unlike [`ExecutionContext::feed_one_source`], the caller doesn't keep
+    /// its own copy of the record batches while the plan runs: once fed, the
+    /// plan holds the only references to their buffers.
+    pub fn feed_one_source_owned(&mut self, partitions: Vec<Vec<RecordBatch>>) -> Result<()> {
+        self.feed_partitions(partitions)
+    }
+
+    /// Feed one data source to the execution plan, keeping only the columns
+    /// that the data source of the plan outputs.
+    ///
+    /// The scan of a plan may already be projected, in which case
+    /// [`ExecutionContext::feed_one_source`] holds the full batches and
+    /// projects them at execution. Here the batches are projected once, at the
+    /// feed boundary, so the unused columns are released right away.
+    ///
+    /// Returns an error if a column of the scan is missing from the batches or
+    /// has another type.
+    pub fn feed_one_source_projected(&mut self, partitions: Vec<Vec<RecordBatch>>) -> Result<()> {
+        let leaf = self.first_leaf().ok_or_else(|| {
+            SquirtleError::Execution("No data source in the execution plan.".to_string())
+        })?;
+        let schema = leaf.schema();
+        let partitions = partitions
+            .into_iter()
+            .map(|partition| {
+                partition
+                    .iter()
+                    .map(|batch| project(batch, &schema))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let target = Arc::as_ptr(&leaf) as *const u8;
+        self.plan = feed_leaves(&self.plan, &mut |leaf| {
+            if Arc::as_ptr(leaf) as *const u8 == target {
+                Ok(Some(Arc::new(MemoryExec::try_new(
+                    &partitions,
+                    schema.clone(),
+                    None,
+                )?)))
+            } else {
+                Ok(None)
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Feed one data source to the execution plan, then read the rows back
+    /// from the leaf `MemoryExec` and check that they match the input.
+    ///
+    /// This is meant for the debug mode. A mismatch means the mutation in
+    /// [`ExecutionContext::feed_one_source`] didn't take effect, e.g., because
+    /// the plan was aliased by another `Arc`.
+    pub async fn feed_one_source_checked(
+        &mut self,
+        partitions: &Vec<Vec<RecordBatch>>,
+    ) -> Result<()> {
+        self.feed_one_source(partitions)?;
+
+        let leaf = self.first_leaf().ok_or_else(|| {
+            SquirtleError::Execution("No data source in the execution plan.".to_string())
+        })?;
+        let expected: usize = partitions.iter().flatten().map(|b| b.num_rows()).sum();
+        let actual: usize = collect(leaf).await?.iter().map(|b| b.num_rows()).sum();
+        if expected != actual {
+            return Err(SquirtleError::Execution(format!(
+                "Fed {} rows to the execution plan, but its data source has {} rows.",
+                expected, actual
+            )));
+        }
+        Ok(())
+    }
+
+    /// Feed one data source, encoded as an Arrow IPC stream, to the execution
+    /// plan. The record batches are read straight from the stream into the
+    /// partition fed to the plan.
+    ///
+    /// Returns an error if the schema of the stream differs from the schema of
+    /// the data source in the plan.
+    pub fn feed_one_source_ipc(&mut self, ipc_bytes: &[u8]) -> Result<()> {
+        let reader = StreamReader::try_new(ipc_bytes)?;
+        let leaf = self.first_leaf().ok_or_else(|| {
+            SquirtleError::Execution("No data source in the execution plan.".to_string())
+        })?;
+        if reader.schema() != leaf.schema() {
+            return Err(SquirtleError::Execution(format!(
+                "The schema of the IPC stream {:?} doesn't match the data source {:?}.",
+                reader.schema(),
+                leaf.schema()
+            )));
+        }
+
+        let batches = reader.collect::<ArrowResult<Vec<_>>>()?;
+        self.feed_one_source_owned(vec![batches])
+    }
+
+    /// Returns the depth of the execution plan, i.e., the number of nodes on
+    /// the longest path from the root to a leaf.
+    pub fn plan_depth(&self) -> usize {
         // Breadth-first search
+        let mut depth = 0;
         let mut queue = VecDeque::new();
-        queue.push_front(self.plan().clone());
+        queue.push_back((self.plan.clone(), 1));
+        while let Some((p, d)) = queue.pop_front() {
+            depth = depth.max(d);
+            queue.extend(p.children().into_iter().map(|c| (c, d + 1)));
+        }
+        depth
+    }
+
+    /// Rejects a plan deeper than `max_depth`, before the recursive traversals
+    /// of the plan (such as `feed_one_source`) can exhaust the stack.
+    pub fn check_plan_depth(&self, max_depth: usize) -> Result<()> {
+        let depth = self.plan_depth();
+        if depth > max_depth {
+            return Err(SquirtleError::Plan(format!(
+                "The plan of {} is {} operators deep, more than the maximum of {}.",
+                self.name, depth, max_depth
+            )));
+        }
+        Ok(())
+    }
 
-        while !queue.is_empty() {
-            let mut p = queue.pop_front().unwrap();
+    /// Checks that every leaf of the plan can be fed by one of the `available`
+    /// source schemas before the plan is executed.
+    ///
+    /// A source satisfies a leaf if it has every column of the leaf, by name
+    /// and type, as required by
+    /// [`ExecutionContext::feed_one_source_projected`]. Returns an error
+    /// naming the first leaf that no source satisfies.
+    pub fn verify_schema(&self, available: &[SchemaRef]) -> Result<()> {
+        let mut leaves = vec![];
+        let mut queue = VecDeque::new();
+        queue.push_back(self.plan.clone());
+        while let Some(p) = queue.pop_front() {
             if p.children().is_empty() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut p)
-                        .as_mut_any()
-                        .downcast_mut::<MemoryExec>()
-                        .unwrap()
-                        .set_partitions(partitions);
-                }
-                break;
+                leaves.push(p);
+            } else {
+                queue.extend(p.children());
             }
+        }
 
-            p.children()
-                .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(p.children()[i].clone()));
+        for (i, leaf) in leaves.iter().enumerate() {
+            let required = leaf.schema();
+            if !available.iter().any(|source| satisfies(source, &required)) {
+                return Err(SquirtleError::Plan(format!(
+                    "Leaf {} of {} ({}) can't be fed by any of the {} available sources.",
+                    i,
+                    self.name,
+                    summarize(leaf),
+                    available.len()
+                )));
+            }
         }
+        Ok(())
+    }
+
+    /// Checks that the plan can be serialized, which marshalling the context
+    /// for a cloud function requires, before the context is deployed.
+    ///
+    /// Returns an error naming the innermost operator that fails to serialize.
+    pub fn check_serializable(&self) -> Result<()> {
+        let serialize = |plan: &Arc<dyn ExecutionPlan>| serde_json::to_value(plan).map(|_| ());
+        find_unserializable(&self.plan, &serialize).map_err(|(operator, e)| {
+            SquirtleError::Plan(format!(
+                "The plan of {} can't be serialized at {}: {}",
+                self.name, operator, e
+            ))
+        })
     }
 
-    /// Feed two data sources to the execution plan like join two tables.
-    pub fn feed_two_source(&mut self, left: &Vec<Vec<RecordBatch>>, right: &Vec<Vec<RecordBatch>>) {
+    /// Returns the number of nodes in the execution plan.
+    pub fn num_nodes(&self) -> usize {
         // Breadth-first search
+        let mut num_nodes = 0;
         let mut queue = VecDeque::new();
-        queue.push_front(self.plan().clone());
+        queue.push_back(self.plan.clone());
+        while let Some(p) = queue.pop_front() {
+            num_nodes += 1;
+            queue.extend(p.children());
+        }
+        num_nodes
+    }
+
+    /// Returns a one-line summary of the execution plan for logging, such as
+    /// `Projection ← Join(Scan(a, b)[1 parts], Scan(c, d)[1 parts])`.
+    ///
+    /// Each operator is followed by its input; the inputs of an operator with
+    /// several children are listed in parentheses. The leaves show their
+    /// columns and partition count, since the memory tables carry no names.
+    pub fn plan_summary(&self) -> String {
+        summarize(&self.plan)
+    }
 
-        while !queue.is_empty() {
-            let mut p = queue.pop_front().unwrap();
+    /// Returns the first leaf of the execution plan in breadth-first order.
+    fn first_leaf(&self) -> Option<Arc<dyn ExecutionPlan>> {
+        // Breadth-first search
+        let mut queue = VecDeque::new();
+        queue.push_back(self.plan.clone());
+        while let Some(p) = queue.pop_front() {
             if p.children().is_empty() {
-                // Schema comparsion
-                for partition in &[&left, &right] {
-                    if p.schema() == partition[0][0].schema() {
-                        unsafe {
-                            Arc::get_mut_unchecked(&mut p)
-                                .as_mut_any()
-                                .downcast_mut::<MemoryExec>()
-                                .unwrap()
-                                .set_partitions(partition);
-                        }
-                        break;
-                    }
-                }
+                return Some(p);
+            }
+            queue.extend(p.children());
+        }
+        None
+    }
+
+    /// Returns the number of partitions the operators above the leaves expect.
+    ///
+    /// This is the partition count of the `RepartitionExec` closest to the
+    /// leaves of the plan. If the plan doesn't repartition its input, the
+    /// configured `parallelism` is used instead.
+    pub fn target_partitions(&self) -> usize {
+        let mut target = None;
+
+        // Breadth-first search
+        let mut queue = VecDeque::new();
+        queue.push_back(self.plan.clone());
+        while let Some(p) = queue.pop_front() {
+            if let Some(repartition) = p.as_any().downcast_ref::<RepartitionExec>() {
+                target = Some(repartition.partitioning().partition_count());
+            }
+            queue.extend(p.children());
+        }
+
+        target.unwrap_or_else(|| globals["lambda"]["parallelism"].parse::<usize>().unwrap())
+    }
+
+    /// Repartitions the input batches before feeding them to the execution
+    /// plan, so that the intra-function parallelism of the operators above
+    /// the leaf is fully used.
+    ///
+    /// If `partitioning` is `None`, the batches are distributed round-robin
+    /// into [`ExecutionContext::target_partitions`] partitions, capped by the
+    /// number of input batches.
+    ///
+    /// Empty input partitions are dropped, and an error is returned if no
+    /// batch is left.
+    pub async fn feed_one_source_repartitioned(
+        &mut self,
+        partitions: Vec<Vec<RecordBatch>>,
+        partitioning: Option<Partitioning>,
+    ) -> Result<()> {
+        // the repartitioning takes the schema from the first batch.
+        let partitions: Vec<_> = partitions.into_iter().filter(|p| !p.is_empty()).collect();
+        let num_batches: usize = partitions.iter().map(|p| p.len()).sum();
+        if num_batches == 0 {
+            return Err(SquirtleError::Execution(
+                "No record batches to feed.".to_string(),
+            ));
+        }
+
+        let partitioning = partitioning.unwrap_or_else(|| {
+            Partitioning::RoundRobinBatch(self.target_partitions().min(num_batches).max(1))
+        });
+
+        // round-robin partitions of the right count can be fed as they are,
+        // but hash partitioning must always place the rows by key.
+        match partitioning {
+            Partitioning::RoundRobinBatch(n) if n == partitions.len() => {
+                self.feed_one_source_owned(partitions)
+            }
+            _ => self.feed_one_source_owned(
+                LambdaExecutor::repartition(partitions, partitioning).await?,
+            ),
+        }
+    }
+
+    /// Feed the data sources to the leaves of the execution plan with the same
+    /// schemas.
+    ///
+    /// The sources are indexed by schema once, and each leaf is assigned its
+    /// source by a lookup. Schemas are compared field by field in order, so
+    /// duplicate field names are told apart by their position. If several
+    /// sources have the same schema, as in a self-join, the leaves of that
+    /// schema are fed the sources in order.
+    ///
+    /// Returns an error if a source is empty, if a leaf is left without a
+    /// source, or if a source isn't fed to any leaf.
+    pub fn feed_data_sources(&mut self, sources: &[&Vec<Vec<RecordBatch>>]) -> Result<()> {
+        let mut index: HashMap<String, VecDeque<usize>> = HashMap::new();
+        for (i, source) in sources.iter().enumerate() {
+            let schema = source
+                .iter()
+                .flatten()
+                .next()
+                .map(|batch| batch.schema())
+                .ok_or_else(|| {
+                    SquirtleError::Execution(format!("The data source #{} is empty.", i))
+                })?;
+            index.entry(schema_key(&schema)).or_default().push_back(i);
+        }
+
+        let mut fed = vec![false; sources.len()];
+        let plan = feed_leaves(&self.plan, &mut |leaf| match index
+            .get_mut(&schema_key(&leaf.schema()))
+            .and_then(|sources| sources.pop_front())
+        {
+            Some(i) => {
+                fed[i] = true;
+                Ok(Some(memory_exec(leaf, sources[i])?))
             }
+            None if leaf.as_any().is::<MemoryExec>() => Err(SquirtleError::Execution(format!(
+                "No data source for the leaf {:?}",
+                leaf.schema()
+            ))),
+            None => Ok(None),
+        })?;
+        if let Some(i) = fed.iter().position(|fed| !fed) {
+            return Err(SquirtleError::Execution(format!(
+                "The data source #{} doesn't match any leaf of the execution plan.",
+                i
+            )));
+        }
+
+        self.plan = plan;
+        Ok(())
+    }
+
+    /// Feed two data sources to the execution plan like join two tables. See
+    /// [`ExecutionContext::feed_data_sources`].
+    pub fn feed_two_source(
+        &mut self,
+        left: &Vec<Vec<RecordBatch>>,
+        right: &Vec<Vec<RecordBatch>>,
+    ) -> Result<()> {
+        self.feed_data_sources(&[left, right])
+    }
+}
 
-            p.children()
+/// Summarizes the subtree rooted at `plan`. See
+/// [`ExecutionContext::plan_summary`].
+fn summarize(plan: &Arc<dyn ExecutionPlan>) -> String {
+    let children = plan.children();
+    match children.len() {
+        0 => format!(
+            "{}({})[{} parts]",
+            operator_name(plan),
+            plan.schema()
+                .fields()
                 .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(p.children()[i].clone()));
+                .map(|f| f.name().as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            plan.output_partitioning().partition_count()
+        ),
+        1 => format!("{} ← {}", operator_name(plan), summarize(&children[0])),
+        _ => format!(
+            "{}({})",
+            operator_name(plan),
+            children
+                .iter()
+                .map(summarize)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Returns the name of the innermost operator of `plan` that `serialize`
+/// fails on, and the error. See [`ExecutionContext::check_serializable`].
+fn find_unserializable(
+    plan: &Arc<dyn ExecutionPlan>,
+    serialize: &dyn Fn(&Arc<dyn ExecutionPlan>) -> serde_json::Result<()>,
+) -> std::result::Result<(), (String, serde_json::Error)> {
+    if let Err(e) = serialize(plan) {
+        for child in plan.children() {
+            find_unserializable(&child, serialize)?;
         }
+        return Err((operator_name(plan), e));
+    }
+    Ok(())
+}
+
+/// Returns true if `source` has every column of `required` with the same type.
+fn satisfies(source: &Schema, required: &Schema) -> bool {
+    required.fields().iter().all(|field| {
+        source
+            .field_with_name(field.name())
+            .map_or(false, |f| f.data_type() == field.data_type())
+    })
+}
+
+/// Returns a short name of the operator, e.g. `Filter` for `FilterExec`.
+fn operator_name(plan: &Arc<dyn ExecutionPlan>) -> String {
+    let any = plan.as_any();
+    if any.is::<MemoryExec>() || any.is::<EmptyExec>() {
+        return "Scan".to_owned();
+    }
+    if any.is::<HashAggregateExec>() {
+        return "Aggregate".to_owned();
+    }
+    if any.is::<HashJoinExec>() {
+        return "Join".to_owned();
+    }
+
+    // The other operators are named after their type, from the debug output.
+    let debug = format!("{:?}", plan);
+    let name = debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    name.trim_end_matches("Exec").to_owned()
+}
+
+/// Rebuilds the plan bottom-up, replacing each node for which `rewrite`
+/// returns a new node.
+fn rewrite_nodes<F>(plan: &Arc<dyn ExecutionPlan>, rewrite: &mut F) -> Arc<dyn ExecutionPlan>
+where
+    F: FnMut(&Arc<dyn ExecutionPlan>) -> Option<Arc<dyn ExecutionPlan>>,
+{
+    let children = plan.children();
+    let plan = if children.is_empty() {
+        plan.clone()
+    } else {
+        let children = children
+            .iter()
+            .map(|child| rewrite_nodes(child, rewrite))
+            .collect::<Vec<_>>();
+        // The new children have the same schemas as the old ones.
+        plan.with_new_children(children).unwrap()
+    };
+    rewrite(&plan).unwrap_or(plan)
+}
+
+/// Rebuilds the execution plan bottom-up, replacing each leaf for which `feed`
+/// returns a new node. The other nodes are rebuilt with `with_new_children`,
+/// so the original plan is left untouched.
+fn feed_leaves<F>(plan: &Arc<dyn ExecutionPlan>, feed: &mut F) -> Result<Arc<dyn ExecutionPlan>>
+where
+    F: FnMut(&Arc<dyn ExecutionPlan>) -> Result<Option<Arc<dyn ExecutionPlan>>>,
+{
+    let children = plan.children();
+    if children.is_empty() {
+        return Ok(feed(plan)?.unwrap_or_else(|| plan.clone()));
+    }
+
+    let children = children
+        .iter()
+        .map(|child| feed_leaves(child, feed))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(plan.with_new_children(children)?)
+}
+
+/// The key of a schema in the index of the data sources: its fields, in order.
+fn schema_key(schema: &SchemaRef) -> String {
+    format!("{:?}", schema.fields())
+}
+
+/// Returns the columns of the batch that make up `schema`, looked up by name.
+fn project(batch: &RecordBatch, schema: &SchemaRef) -> Result<RecordBatch> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| Ok(batch.column(batch.schema().index_of(field.name())?).clone()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Returns a new `MemoryExec` with the same schema and projection as the leaf,
+/// carrying the given partitions.
+fn memory_exec(
+    leaf: &Arc<dyn ExecutionPlan>,
+    partitions: &Vec<Vec<RecordBatch>>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    // The record batches are skipped by serialization, so the roundtrip gives
+    // a cheap copy of the leaf that no other `Arc` refers to.
+    let mut exec: Arc<dyn ExecutionPlan> = serde_json::from_value(serde_json::to_value(leaf)?)?;
+    Arc::get_mut(&mut exec)
+        .and_then(|p| p.as_mut_any().downcast_mut::<MemoryExec>())
+        .ok_or_else(|| {
+            SquirtleError::Execution("The data source is not a MemoryExec.".to_string())
+        })?
+        .set_partitions(partitions);
+    Ok(exec)
+}
+
+/// Returns a context of the physical plan of `sql` over `tables`, each a table
+/// name and its partitions. Like the plans shipped to the cloud functions, the
+/// plan is serialized and deserialized, so its record batches are stripped and
+/// must be fed again.
+#[cfg(test)]
+pub(crate) fn build_test_context(
+    sql: &str,
+    tables: &[(&str, &Vec<Vec<RecordBatch>>)],
+) -> Result<ExecutionContext> {
+    let mut ctx = datafusion::execution::context::ExecutionContext::new();
+    for (name, partitions) in tables {
+        let schema = partitions
+            .iter()
+            .flatten()
+            .next()
+            .map(|batch| batch.schema())
+            .ok_or_else(|| SquirtleError::Internal(format!("Table {} has no batches", name)))?;
+        let table = datafusion::datasource::MemTable::try_new(schema, (*partitions).clone())?;
+        ctx.register_table(*name, Arc::new(table))?;
     }
+
+    let logical_plan = ctx.create_logical_plan(sql)?;
+    let logical_plan = ctx.optimize(&logical_plan)?;
+    let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+    let plan = serde_json::to_string(&physical_plan)?;
+
+    Ok(ExecutionContext {
+        plan: serde_json::from_str(&plan)?,
+        name: "test".to_owned(),
+        ..Default::default()
+    })
 }
 
 #[cfg(test)]
@@ -259,12 +1278,17 @@ mod tests {
     use crate::error::Result;
 
     use crate::datasource::kinesis;
+    use crate::naming::group_member;
     use aws_lambda_events::event::kinesis::KinesisEvent;
     use datafusion::datasource::MemTable;
     use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::common;
+    use datafusion::physical_plan::expressions::col;
 
+    use crate::encoding::ZSTD_LDM_WINDOW_LOG;
     use arrow::array::*;
     use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::ipc::writer::StreamWriter;
     use arrow::record_batch::RecordBatch;
 
     #[tokio::test]
@@ -286,47 +1310,617 @@ mod tests {
             ..Default::default()
         };
 
-        let json = lambda_context.marshal(Encoding::default());
-        let de_json = ExecutionContext::unmarshal(&json);
+        let json = lambda_context.marshal(Encoding::default())?;
+        let de_json = ExecutionContext::unmarshal(&json)?;
         assert_eq!(lambda_context, de_json);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn feed_one_source() -> Result<()> {
-        let input = include_str!("../../test/data/example-kinesis-event-1.json");
-        let input: KinesisEvent = serde_json::from_str(input).unwrap();
-        let partitions = vec![kinesis::to_batch(input)];
+    async fn marshal_tiny_context() -> Result<()> {
+        let ctx = ExecutionContext::default();
+        assert!(serde_json::to_vec(&ctx)?.len() < COMPRESSION_THRESHOLD);
 
-        let mut ctx = datafusion::execution::context::ExecutionContext::new();
-        let provider = MemTable::try_new(partitions[0][0].schema(), partitions.clone())?;
+        let uncompressed = ctx.marshal(Encoding::None)?;
+        for encoding in &[Encoding::Snappy, Encoding::Lz4, Encoding::Zstd] {
+            let json = ctx.marshal(encoding.clone())?;
+            assert!(json.len() <= uncompressed.len());
 
-        ctx.register_table("test", Arc::new(provider))?;
+            // The encoding actually used is recorded, not the requested one.
+            let env: CloudEnvironment = serde_json::from_str(&json)?;
+            assert_eq!(Encoding::None, env.encoding);
+            assert_eq!(env, ctx.to_cloud_environment(encoding.clone())?);
+            assert_eq!(ctx, ExecutionContext::unmarshal(&json)?);
+        }
 
-        let sql = "SELECT MAX(c1), MIN(c2), c3 FROM test WHERE c2 < 99 GROUP BY c3";
-        let logical_plan = ctx.create_logical_plan(&sql)?;
-        let logical_plan = ctx.optimize(&logical_plan)?;
-        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let json = ctx.marshal_ldm(ZSTD_LDM_WINDOW_LOG)?;
+        let env: CloudEnvironment = serde_json::from_str(&json)?;
+        assert_eq!(Encoding::None, env.encoding);
+        assert_eq!(None, env.ldm_window_log);
+        assert_eq!(ctx, ExecutionContext::unmarshal(&json)?);
 
-        // Serialize the physical plan and skip its record batches
-        let plan = serde_json::to_string(&physical_plan)?;
+        // A malformed context is an error rather than a panic.
+        assert!(ExecutionContext::unmarshal(&json[1..]).is_err());
+        assert!(ExecutionContext::unmarshal(
+            r#"{"context":"AAAA","encoding":"None","ldm_window_log":null}"#
+        )
+        .is_err());
 
-        // Deserialize the physical plan that doesn't contain record batches
-        let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cloud_function_members() {
+        let members = CloudFunction::Chorus(("q5-01".to_owned(), 12)).member_names();
+        assert_eq!(12, members.len());
+        assert_eq!(
+            (0..12).map(|i| format!("q5-01-{}", i)).collect::<Vec<_>>(),
+            members
+        );
+
+        assert_eq!(
+            vec!["q5-00"],
+            CloudFunction::Solo("q5-00".to_owned()).member_names()
+        );
+        assert!(CloudFunction::Sink(DataSinkType::Stdout)
+            .member_names()
+            .is_empty());
+    }
+
+    #[test]
+    fn max_plan_depth() -> Result<()> {
+        let mut plan: Arc<dyn ExecutionPlan> =
+            Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())));
+        for _ in 1..1000 {
+            plan = Arc::new(CoalesceBatchesExec::new(plan, 1024));
+        }
+        let ctx = ExecutionContext {
+            plan,
+            ..Default::default()
+        };
+        assert_eq!(1000, ctx.plan_depth());
+        assert!(ctx.check_plan_depth(1000).is_ok());
+        assert!(ctx.check_plan_depth(999).is_err());
+
+        // A deep plan is rejected when the context is loaded.
+        let mut ctx = ExecutionContext::default();
+        for _ in 0..globals["lambda"]["max_plan_depth"]
+            .parse::<usize>()
+            .unwrap()
+        {
+            ctx.plan = Arc::new(CoalesceBatchesExec::new(ctx.plan.clone(), 1024));
+        }
+        assert!(ExecutionContext::unmarshal(&ctx.marshal(Encoding::Zstd)?).is_err());
+        ctx.plan = ctx.plan.children()[0].clone();
+        assert_eq!(
+            ctx,
+            ExecutionContext::unmarshal(&ctx.marshal(Encoding::Zstd)?)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn clone_shares_plan() {
+        let mut plan: Arc<dyn ExecutionPlan> =
+            Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())));
+        for _ in 0..1000 {
+            plan = Arc::new(CoalesceBatchesExec::new(plan, 1024));
+        }
+        let ctx = ExecutionContext {
+            plan,
+            ..Default::default()
+        };
+
+        // The members of a large function group share a single plan.
+        let members: Vec<_> = (0..1024)
+            .map(|i| ExecutionContext {
+                name: group_member("q5-01", i),
+                ..ctx.clone()
+            })
+            .collect();
+        assert!(members.iter().all(|m| Arc::ptr_eq(&m.plan, &ctx.plan)));
+        assert_eq!(1 + members.len(), Arc::strong_count(&ctx.plan));
+    }
+
+    #[test]
+    fn feature_flags() -> Result<()> {
+        // Contexts marshalled before the flags existed still unmarshal.
+        let ctx = ExecutionContext::default();
+        let mut legacy = serde_json::to_value(&ctx)?;
+        assert!(legacy.get("flags").is_none());
+        legacy.as_object_mut().unwrap().remove("shuffle_keys");
+        assert_eq!(ctx, serde_json::from_value(legacy)?);
+
+        let mut ctx = ExecutionContext::default();
+        ctx.flags
+            .insert(VALIDATE_FLAG.to_owned(), "true".to_owned());
+        ctx.flags.insert("encoding".to_owned(), "lz4".to_owned());
+        assert!(ctx.flag_enabled(VALIDATE_FLAG));
+        assert!(!ctx.flag_enabled("encoding"));
+        assert!(!ctx.flag_enabled("metrics"));
+        assert_eq!(Some("lz4"), ctx.flag("encoding"));
+        assert_eq!(
+            ctx,
+            ExecutionContext::unmarshal(&ctx.marshal(Encoding::Zstd)?)?
+        );
+        assert_ne!(ctx, ExecutionContext::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn display_summary() -> Result<()> {
+        assert_eq!(
+            "→ q5-01",
+            CloudFunction::Solo("q5-01".to_owned()).to_string()
+        );
+        assert_eq!(
+            "→ group q5-02 ×8",
+            CloudFunction::Chorus(("q5-02".to_owned(), 8)).to_string()
+        );
+        assert_eq!(
+            "⇒ sink: stdout",
+            CloudFunction::Sink(DataSinkType::Stdout).to_string()
+        );
+
+        let plan = r#"{"execution_plan":"coalesce_batches_exec","input":{"execution_plan":"memory_exec","schema":{"fields":[{"name":"c1","data_type":"Int64","nullable":true,"dict_id":0,"dict_is_ordered":false}],"metadata":{}},"projection":null},"target_batch_size":16384}"#;
+        let ctx = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            name: "q5-00".to_owned(),
+            next: CloudFunction::Chorus(("q5-01".to_owned(), 8)),
+            ..Default::default()
+        };
+        assert_eq!(2, ctx.num_nodes());
+        assert_eq!("q5-00 (2 nodes) → group q5-01 ×8", ctx.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_size_flag() -> Result<()> {
+        let plan = r#"{"execution_plan":"coalesce_batches_exec","input":{"execution_plan":"memory_exec","schema":{"fields":[{"name":"c1","data_type":"Int64","nullable":true,"dict_id":0,"dict_is_ordered":false}],"metadata":{}},"projection":null},"target_batch_size":16384}"#;
+        let mut ctx = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            name: "q5-00".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            globals["lambda"]["target_batch_size"]
+                .parse::<usize>()
+                .unwrap(),
+            ctx.target_batch_size()?
+        );
+
+        // The flag is applied to the plan when the context is loaded.
+        ctx.flags
+            .insert(BATCH_SIZE_FLAG.to_owned(), "1024".to_owned());
+        let loaded = ExecutionContext::unmarshal(&ctx.marshal(Encoding::Zstd)?)?;
+        assert_eq!(1024, loaded.target_batch_size()?);
+        let plan = serde_json::to_string(&loaded.plan)?;
+        assert!(plan.contains(r#""target_batch_size":1024"#));
+        assert!(!plan.contains("16384"));
+
+        ctx.flags.insert(BATCH_SIZE_FLAG.to_owned(), "0".to_owned());
+        assert!(ctx.target_batch_size().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn event_time_watermark() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "b_date_time",
+            DataType::Date64,
+            false,
+        )]));
+        let batches = vec![
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Date64Array::from(vec![1_000, 3_000, 2_000]))],
+            )?,
+            RecordBatch::try_new(schema, vec![Arc::new(Date64Array::from(vec![2_500]))])?,
+        ];
+
+        let mut ctx = ExecutionContext::default();
+        assert_eq!(None, ctx.event_time_watermark(&batches)?);
+
+        ctx.flags
+            .insert(EVENT_TIME_FLAG.to_owned(), "b_date_time".to_owned());
+        assert_eq!(Some(3_000), ctx.event_time_watermark(&batches)?);
+        assert_eq!(None, ctx.event_time_watermark(&[])?);
+
+        // The watermark is set per invocation and never marshalled.
+        ctx.watermark = Some(3_000);
+        let loaded = ExecutionContext::unmarshal(&ctx.marshal(Encoding::Zstd)?)?;
+        assert_eq!(None, loaded.watermark);
+
+        ctx.flags
+            .insert(EVENT_TIME_FLAG.to_owned(), "no_such_column".to_owned());
+        assert!(ctx.event_time_watermark(&batches).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_version() -> Result<()> {
+        let ctx = ExecutionContext {
+            name: "q-00".to_owned(),
+            ..Default::default()
+        };
+        let marshalled = ctx.marshal(Encoding::Zstd)?;
+        let env: CloudEnvironment = serde_json::from_str(&marshalled)?;
+        assert_eq!(Some(RUNTIME_VERSION), env.version.as_deref());
+        assert_eq!(ctx, ExecutionContext::unmarshal(&marshalled)?);
+
+        // Contexts marshalled before the version was recorded are accepted.
+        let legacy = CloudEnvironment {
+            version: None,
+            ..env.clone()
+        };
+        assert!(!serde_json::to_string(&legacy)?.contains("version"));
+        assert_eq!(
+            ctx,
+            ExecutionContext::unmarshal(&serde_json::to_string(&legacy)?)?
+        );
+
+        // A patch release is compatible, a breaking release is not.
+        let patched = CloudEnvironment {
+            version: Some(format!("{}-patched", RUNTIME_VERSION)),
+            ..env.clone()
+        };
+        assert!(patched.check_version().is_ok());
+        let breaking = CloudEnvironment {
+            version: Some("99.0.0".to_owned()),
+            ..env
+        };
+        assert!(breaking.check_version().is_err());
+        assert!(ExecutionContext::unmarshal(&serde_json::to_string(&breaking)?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn base64_envelope() -> Result<()> {
+        #[derive(Serialize)]
+        struct ArrayEnvelope {
+            #[serde(with = "serde_bytes")]
+            context:        Vec<u8>,
+            encoding:       Encoding,
+            ldm_window_log: Option<u32>,
+        }
+
+        // Compressed contexts look like random bytes.
+        let context: Vec<u8> = (0..2048_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let env = CloudEnvironment {
+            context:        context.clone(),
+            encoding:       Encoding::Zstd,
+            ldm_window_log: None,
+            version:        None,
+        };
+        let base64 = serde_json::to_string(&env)?;
+        let array = serde_json::to_string(&ArrayEnvelope {
+            context,
+            encoding: Encoding::Zstd,
+            ldm_window_log: None,
+        })?;
+
+        // base64 takes 4 characters per 3 bytes, while a JSON array takes
+        // between 2 and 4 characters per byte.
+        assert!(base64.len() < 2048 * 4 / 3 + 64);
+        assert!(base64.len() * 2 < array.len());
+
+        // Both envelopes are deserialized to the same context.
+        assert_eq!(env, serde_json::from_str::<CloudEnvironment>(&base64)?);
+        assert_eq!(env, serde_json::from_str::<CloudEnvironment>(&array)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plan_fingerprint() -> Result<()> {
+        let plan = r#"{"execution_plan":"coalesce_batches_exec","input":{"execution_plan":"memory_exec","schema":{"fields":[{"name":"c1","data_type":"Int64","nullable":true,"dict_id":0,"dict_is_ordered":false}],"metadata":{}},"projection":null},"target_batch_size":16384}"#;
+        let other_plan = plan.replace("16384", "4096");
+        let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
+
+        let ctx = ExecutionContext {
+            plan: plan.clone(),
+            name: "hello".to_owned(),
+            ..Default::default()
+        };
+        let renamed = ExecutionContext {
+            plan: plan.clone(),
+            name: "world".to_owned(),
+            next: CloudFunction::Solo("next".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(16, ctx.fingerprint()?.len());
+        assert_eq!(ctx.fingerprint()?, renamed.fingerprint()?);
+
+        let other = ExecutionContext {
+            plan: serde_json::from_str(&other_plan)?,
+            ..ctx.clone()
+        };
+        assert_ne!(ctx.fingerprint()?, other.fingerprint()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let partitions = vec![kinesis::to_batch(input)];
+
+        let sql = "SELECT MAX(c1), MIN(c2), c3 FROM test WHERE c2 < 99 GROUP BY c3";
+        let mut ctx = build_test_context(sql, &[("test", &partitions)])?;
+
+        // The plan doesn't contain record batches, so feed them back to it.
+        let shared = ctx.plan.clone();
+        ctx.feed_one_source(&partitions)?;
+
+        // The plan shared with another `Arc` is left untouched.
+        assert!(collect(shared).await?.is_empty());
+
+        let batches = collect(ctx.plan.clone()).await?;
+
+        let expected = vec![
+            "+--------------+--------------+----+",
+            "| MAX(test.c1) | MIN(test.c2) | c3 |",
+            "+--------------+--------------+----+",
+            "| 100          | 92.1         | a  |",
+            "+--------------+--------------+----+",
+        ];
+
+        test_utils::assert_batches_eq!(&expected, &batches);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_partitions() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let batches = kinesis::to_batch(input);
+        let schema = batches[0].schema();
+        let num_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+
+        let mut ctx = ExecutionContext {
+            plan: Arc::new(MemoryExec::try_new(&[], schema, None)?),
+            ..Default::default()
+        };
+
+        // One partition with all the batches.
+        ctx.feed_single_partition(batches.clone())?;
+        let leaf = ctx.first_leaf().unwrap();
+        assert_eq!(1, leaf.output_partitioning().partition_count());
+        assert_eq!(
+            num_rows,
+            ctx.execute()
+                .await?
+                .iter()
+                .map(|b| b.num_rows())
+                .sum::<usize>()
+        );
+
+        // One partition per batch.
+        ctx.feed_partitions(batches.iter().map(|b| vec![b.clone()]).collect())?;
+        let leaf = ctx.first_leaf().unwrap();
+        assert_eq!(batches.len(), leaf.output_partitioning().partition_count());
+        assert_eq!(
+            num_rows,
+            ctx.execute()
+                .await?
+                .iter()
+                .map(|b| b.num_rows())
+                .sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source_owned() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let partitions = vec![kinesis::to_batch(input.clone())];
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let provider = MemTable::try_new(partitions[0][0].schema(), partitions)?;
+        ctx.register_table("test", Arc::new(provider))?;
+
+        let sql = "SELECT c1 FROM test";
+        let logical_plan = ctx.create_logical_plan(&sql)?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let plan = serde_json::to_string(&physical_plan)?;
+
+        let mut ctx = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            ..Default::default()
+        };
+
+        // The batches are moved into the plan, so the only other reference to
+        // their buffers is the one held by the test.
+        let partitions = vec![kinesis::to_batch(input)];
+        let num_rows: usize = partitions[0].iter().map(|b| b.num_rows()).sum();
+        let column = partitions[0][0].column(0).clone();
+        ctx.feed_one_source_owned(partitions)?;
+        assert_eq!(2, Arc::strong_count(&column));
+
+        let batches = ctx.execute().await?;
+        assert_eq!(
+            num_rows,
+            batches.iter().map(|b| b.num_rows()).sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source_projected() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+            Field::new("c", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+                Arc::new(Int32Array::from(vec![4, 3, 2, 1])),
+            ],
+        )?;
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let provider = MemTable::try_new(schema.clone(), vec![vec![batch.clone()]])?;
+        ctx.register_table("t", Arc::new(provider))?;
+
+        let sql = "SELECT b FROM t WHERE b > 5";
+        let logical_plan = ctx.create_logical_plan(&sql)?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let plan = serde_json::to_string(&physical_plan)?;
+
+        let mut full = ExecutionContext {
+            plan: serde_json::from_str(&plan)?,
+            ..Default::default()
+        };
+        let mut projected = full.clone();
+
+        full.feed_one_source(&vec![vec![batch.clone()]])?;
+        projected.feed_one_source_projected(vec![vec![batch.clone()]])?;
+
+        // Only the column read by the query is fed.
+        let leaf = projected.first_leaf().unwrap();
+        assert_eq!(full.first_leaf().unwrap().schema(), leaf.schema());
+        let fed = collect(leaf).await?;
+        assert_eq!(1, fed[0].num_columns());
+
+        // The results are the same as feeding the full batches.
+        let expected = vec![
+            "+-----+", "| b   |", "+-----+", "| 10  |", "| 10  |", "| 100 |", "+-----+",
+        ];
+        test_utils::assert_batches_eq!(&expected, &full.execute().await?);
+        test_utils::assert_batches_eq!(&expected, &projected.execute().await?);
+
+        // The batches must have the columns of the scan.
+        let other = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("d", DataType::Int32, false)])),
+            vec![Arc::new(Int32Array::from(vec![1]))],
+        )?;
+        assert!(projected
+            .feed_one_source_projected(vec![vec![other]])
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source_checked() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let partitions = vec![kinesis::to_batch(input)];
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let provider = MemTable::try_new(partitions[0][0].schema(), partitions.clone())?;
+        ctx.register_table("test", Arc::new(provider))?;
+
+        let logical_plan = ctx.create_logical_plan("SELECT c1 FROM test")?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let plan: Arc<dyn ExecutionPlan> =
+            serde_json::from_str(&serde_json::to_string(&physical_plan)?)?;
 
-        // Feed record batches back to the plan
         let mut ctx = ExecutionContext {
             plan,
             name: "test".to_string(),
-            next: CloudFunction::None,
-            datasource: DataSource::UnknownEvent,
-            query_number: None,
             ..Default::default()
         };
-        ctx.feed_one_source(&partitions);
+        ctx.feed_one_source_checked(&partitions).await?;
 
         let batches = collect(ctx.plan.clone()).await?;
+        let num_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(
+            partitions[0].iter().map(|b| b.num_rows()).sum::<usize>(),
+            num_rows
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source_ipc() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let batches = kinesis::to_batch(input);
+        let schema = batches[0].schema();
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let provider = MemTable::try_new(schema.clone(), vec![batches.clone()])?;
+        ctx.register_table("test", Arc::new(provider))?;
+
+        let logical_plan = ctx.create_logical_plan("SELECT * FROM test")?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let plan: Arc<dyn ExecutionPlan> =
+            serde_json::from_str(&serde_json::to_string(&physical_plan)?)?;
+
+        let mut ipc_bytes = vec![];
+        {
+            let mut writer = StreamWriter::try_new(&mut ipc_bytes, &schema)?;
+            for batch in &batches {
+                writer.write(batch)?;
+            }
+            writer.finish()?;
+        }
+
+        let mut ctx = ExecutionContext {
+            plan: plan.clone(),
+            name: "test".to_string(),
+            ..Default::default()
+        };
+        ctx.feed_one_source_ipc(&ipc_bytes)?;
+        let output = collect(ctx.plan.clone()).await?;
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+            output.iter().map(|b| b.num_rows()).sum::<usize>()
+        );
+
+        // The schema of the stream doesn't match the data source.
+        let other = Arc::new(Schema::new(vec![Field::new("x", DataType::Int64, false)]));
+        let mut ipc_bytes = vec![];
+        StreamWriter::try_new(&mut ipc_bytes, &other)?.finish()?;
+        let mut ctx = ExecutionContext {
+            plan,
+            name: "test".to_string(),
+            ..Default::default()
+        };
+        assert!(ctx.feed_one_source_ipc(&ipc_bytes).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_into_sink() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let partitions = vec![kinesis::to_batch(input)];
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let provider = MemTable::try_new(partitions[0][0].schema(), partitions.clone())?;
+        ctx.register_table("test", Arc::new(provider))?;
+
+        let sql = "SELECT MAX(c1), MIN(c2), c3 FROM test WHERE c2 < 99 GROUP BY c3";
+        let logical_plan = ctx.create_logical_plan(&sql)?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
+        let plan: Arc<dyn ExecutionPlan> =
+            serde_json::from_str(&serde_json::to_string(&physical_plan)?)?;
 
         let expected = vec![
             "+--------------+--------------+----+",
@@ -336,7 +1930,130 @@ mod tests {
             "+--------------+--------------+----+",
         ];
 
-        test_utils::assert_batches_eq!(&expected, &batches);
+        for sink in &[DataSinkType::Stdout, DataSinkType::Collect] {
+            let mut ctx = ExecutionContext {
+                plan: plan.clone(),
+                name: "collect_into_sink".to_string(),
+                next: CloudFunction::Sink(sink.clone()),
+                ..Default::default()
+            };
+            ctx.feed_one_source(&partitions)?;
+
+            let summary = ctx.collect_into_sink(Some(b"q0")).await?;
+            assert_eq!(1, summary.num_rows);
+            assert_eq!(CloudFunction::Sink(sink.clone()), summary.destination);
+
+            let batches = DataSinkType::collected("q0");
+            if *sink == DataSinkType::Collect {
+                test_utils::assert_batches_eq!(&expected, &batches);
+            } else {
+                assert!(batches.is_empty());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_on_error() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Int64, false),
+        ]));
+        let batch = |b: Vec<i64>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int64Array::from(vec![6, 6])),
+                    Arc::new(Int64Array::from(b)),
+                ],
+            )
+        };
+        // The second batch fails the execution by dividing by zero.
+        let partitions = vec![vec![batch(vec![2, 3])?, batch(vec![0, 1])?]];
+
+        let mut ctx = build_test_context("SELECT a / b AS c FROM t", &[("t", &partitions)])?;
+        ctx.next = CloudFunction::Sink(DataSinkType::Collect);
+        ctx.feed_one_source(&partitions)?;
+        assert_eq!(OnError::Discard, ctx.on_error()?);
+
+        for (policy, flushed) in &[("discard", 0), ("flush_remaining", 2)] {
+            ctx.flags
+                .insert(ON_ERROR_FLAG.to_owned(), policy.to_string());
+            let key = format!("on-error-{}", policy);
+            assert!(ctx.collect_into_sink(Some(key.as_bytes())).await.is_err());
+            let rows: usize = DataSinkType::collected(&key)
+                .iter()
+                .map(|b| b.num_rows())
+                .sum();
+            assert_eq!(*flushed, rows);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_one_source_repartitioned() -> Result<()> {
+        let input = include_str!("../../test/data/example-kinesis-event-1.json");
+        let input: KinesisEvent = serde_json::from_str(input).unwrap();
+        let partitions = vec![kinesis::to_batch(input)];
+
+        let sql = "SELECT MAX(c1), MIN(c2), c3 FROM test WHERE c2 < 99 GROUP BY c3";
+        let ctx = build_test_context(sql, &[("test", &partitions)])?;
+
+        let expected = vec![
+            "+--------------+--------------+----+",
+            "| MAX(test.c1) | MIN(test.c2) | c3 |",
+            "+--------------+--------------+----+",
+            "| 100          | 92.1         | a  |",
+            "+--------------+--------------+----+",
+        ];
+
+        for partitioning in vec![
+            None,
+            Some(Partitioning::RoundRobinBatch(1)),
+            Some(Partitioning::RoundRobinBatch(2)),
+            Some(Partitioning::RoundRobinBatch(4)),
+            Some(Partitioning::RoundRobinBatch(8)),
+        ] {
+            let mut ctx = ctx.clone();
+            ctx.feed_one_source_repartitioned(partitions.clone(), partitioning)
+                .await?;
+
+            let batches = collect(ctx.plan.clone()).await?;
+            test_utils::assert_batches_eq!(&expected, &batches);
+        }
+
+        // An empty first partition doesn't fail the repartitioning.
+        let mut fed = ctx.clone();
+        fed.feed_one_source_repartitioned(vec![vec![], partitions[0].clone()], None)
+            .await?;
+        test_utils::assert_batches_eq!(&expected, &collect(fed.plan.clone()).await?);
+        assert!(fed
+            .feed_one_source_repartitioned(vec![vec![]], None)
+            .await
+            .is_err());
+
+        // Hash partitioning places each key in a single partition, even if the
+        // input already has as many partitions.
+        let schema = partitions[0][0].schema();
+        let hash = Partitioning::Hash(vec![col("c3", &schema)?], 2);
+        let mut fed = ctx.clone();
+        fed.feed_one_source_repartitioned(vec![partitions[0].clone(); 2], Some(hash))
+            .await?;
+        let leaf = fed.first_leaf().unwrap();
+        assert_eq!(2, leaf.output_partitioning().partition_count());
+        let mut owners = HashMap::new();
+        for i in 0..2 {
+            for batch in common::collect(leaf.execute(i).await?).await? {
+                let keys = batch.column(schema.index_of("c3")?);
+                for row in 0..batch.num_rows() {
+                    let key = arrow::util::display::array_value_to_string(keys, row)?;
+                    assert_eq!(i, *owners.entry(key).or_insert(i));
+                }
+            }
+        }
+        assert!(!owners.is_empty());
 
         Ok(())
     }
@@ -372,43 +2089,25 @@ mod tests {
         let partitions1 = vec![vec![batch1]];
         let partitions2 = vec![vec![batch2]];
 
-        let mut ctx = datafusion::execution::context::ExecutionContext::new();
-
-        let table1 = MemTable::try_new(schema1, partitions1.clone())?;
-        let table2 = MemTable::try_new(schema2, partitions2.clone())?;
-
-        ctx.register_table("t1", Arc::new(table1))?;
-        ctx.register_table("t2", Arc::new(table2))?;
-
         let sql = concat!(
             "SELECT a, b, d ",
             "FROM t1 JOIN t2 ON a = c ",
             "ORDER BY a ASC ",
             "LIMIT 3"
         );
+        let mut ctx = build_test_context(sql, &[("t1", &partitions1), ("t2", &partitions2)])?;
+        // An empty join side is an error rather than a panic.
+        assert!(ctx.feed_two_source(&partitions1, &vec![]).is_err());
+        ctx.feed_two_source(&partitions1, &partitions2)?;
 
-        let logical_plan = ctx.create_logical_plan(&sql)?;
-        let logical_plan = ctx.optimize(&logical_plan)?;
-        let physical_plan = ctx.create_physical_plan(&logical_plan)?;
-
-        // Serialize the physical plan and skip its record batches
-        let plan = serde_json::to_string(&physical_plan)?;
-
-        // Deserialize the physical plan that doesn't contain record batches
-        let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
-
-        // Feed record batches back to the plan
-        let mut ctx = ExecutionContext {
-            plan,
-            name: "test".to_string(),
-            next: CloudFunction::None,
-            datasource: DataSource::UnknownEvent,
-            query_number: None,
-            ..Default::default()
-        };
-        ctx.feed_two_source(&partitions1, &partitions2);
+        let fields = ctx.schema().fields().clone();
+        assert_eq!(
+            vec!["a", "b", "d"],
+            fields.iter().map(|f| f.name().as_str()).collect::<Vec<_>>()
+        );
 
         let batches = collect(ctx.plan.clone()).await?;
+        assert_eq!(ctx.schema(), batches[0].schema());
 
         let expected = vec![
             "+---+----+----+",
@@ -422,6 +2121,177 @@ mod tests {
 
         test_utils::assert_batches_eq!(&expected, &batches);
 
+        // The summary is stable across calls and names both sides of the join.
+        let summary = ctx.plan_summary();
+        assert_eq!(summary, ctx.plan_summary());
+        assert!(summary.contains("Join("));
+        assert!(summary.contains("Scan(a, b)[1 parts]"));
+        assert!(summary.contains("Scan(c, d)[1 parts]"));
+        assert!(!summary.contains('\n'));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_schema() -> Result<()> {
+        let schema1 = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let schema2 = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int32, false),
+        ]));
+        let wider = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int32, false),
+            Field::new("e", DataType::Int64, false),
+        ]));
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        ctx.register_table(
+            "t1",
+            Arc::new(MemTable::try_new(schema1.clone(), vec![vec![]])?),
+        )?;
+        ctx.register_table(
+            "t2",
+            Arc::new(MemTable::try_new(schema2.clone(), vec![vec![]])?),
+        )?;
+        let logical_plan = ctx.create_logical_plan("SELECT a, b, d FROM t1 JOIN t2 ON a = c")?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let ctx = ExecutionContext {
+            plan: ctx.create_physical_plan(&logical_plan)?,
+            name: "q-00".to_owned(),
+            ..Default::default()
+        };
+
+        ctx.verify_schema(&[schema1.clone(), schema2.clone()])?;
+        // A source with extra columns can be projected onto the leaf.
+        ctx.verify_schema(&[schema1.clone(), wider])?;
+
+        // No source has the columns of the right side of the join.
+        let err = ctx.verify_schema(&[schema1.clone()]).unwrap_err();
+        assert!(err.to_string().contains("Scan(c, d)"));
+
+        // A column of the wrong type doesn't satisfy the leaf either.
+        let retyped = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int64, false),
+        ]));
+        assert!(ctx.verify_schema(&[schema1, retyped]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serializable_plan() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let partitions = vec![vec![RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b"])),
+                Arc::new(Int32Array::from(vec![1, 10])),
+            ],
+        )?]];
+        let sql = "SELECT a, MAX(b) FROM t WHERE b > 0 GROUP BY a";
+        let ctx = build_test_context(sql, &[("t", &partitions)])?;
+        ctx.check_serializable()?;
+
+        // An operator that fails to serialize is named, even though the
+        // operators above it fail as well.
+        let err = find_unserializable(&ctx.plan, &|plan| {
+            if summarize(plan).contains("Filter") {
+                Err(serde::ser::Error::custom("no serde"))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+        assert_eq!("Filter", err.0);
+        assert_eq!("no serde", err.1.to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_two_source_self_join() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let left = vec![vec![RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )?]];
+        let right = vec![vec![RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int32Array::from(vec![2, 3, 4]))],
+        )?]];
+
+        let sql = "SELECT t1.a FROM t1 JOIN t2 ON t1.a = t2.a";
+        let mut ctx = build_test_context(sql, &[("t1", &left), ("t2", &right)])?;
+        ctx.feed_two_source(&left, &right)?;
+
+        // Each side of the join is fed its own data source, even though both
+        // have the same schema.
+        let batches = ctx.execute().await?;
+        assert_eq!(2, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_many_sources() -> Result<()> {
+        const NUM_SOURCES: usize = 8;
+
+        let mut sources = vec![];
+        for i in 0..NUM_SOURCES {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new(&format!("k{}", i), DataType::Int32, false),
+                Field::new(&format!("v{}", i), DataType::Int32, false),
+            ]));
+            let batch = RecordBatch::try_new(
+                schema,
+                vec![
+                    Arc::new(Int32Array::from(vec![1, 2, 3])),
+                    Arc::new(Int32Array::from(vec![i as i32; 3])),
+                ],
+            )?;
+            sources.push(vec![vec![batch]]);
+        }
+        let names = (0..NUM_SOURCES)
+            .map(|i| format!("t{}", i))
+            .collect::<Vec<_>>();
+        let tables = names
+            .iter()
+            .map(|name| name.as_str())
+            .zip(sources.iter())
+            .collect::<Vec<_>>();
+
+        // t0 JOIN t1 ON k0 = k1 JOIN t2 ON k1 = k2 ...
+        let sql = (1..NUM_SOURCES).fold("SELECT k0 FROM t0".to_owned(), |sql, i| {
+            format!("{} JOIN t{} ON k{} = k{}", sql, i, i - 1, i)
+        });
+        let ctx = build_test_context(&sql, &tables)?;
+
+        // The sources are assigned by schema, whatever their order.
+        let mut fed = ctx.clone();
+        fed.feed_data_sources(&sources.iter().rev().collect::<Vec<_>>())?;
+        let batches = fed.execute().await?;
+        assert_eq!(3, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        // A missing source leaves a leaf without data.
+        let mut fed = ctx.clone();
+        assert!(fed
+            .feed_data_sources(&sources.iter().skip(1).collect::<Vec<_>>())
+            .is_err());
+
+        // A source that doesn't match any leaf, or is fed twice, is an error.
+        let mut fed = ctx.clone();
+        let mut extra = sources.iter().collect::<Vec<_>>();
+        extra.push(&sources[0]);
+        assert!(fed.feed_data_sources(&extra).is_err());
+
         Ok(())
     }
 }
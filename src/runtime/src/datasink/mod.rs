@@ -0,0 +1,328 @@
+// Copyright (c) 2020 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+// Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! A data sink is the location where the results of a query are sent to at the
+//! end of the dataflow.
+
+use crate::config::GLOBALS as globals;
+use crate::context::CloudFunction;
+use crate::error::{Result, SquirtleError};
+use crate::executor::retry::RetryStats;
+use crate::payload::PayloadStats;
+use arrow::record_batch::RecordBatch;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The record batches buffered by [`DataSinkType::Collect`], keyed by the
+    /// name of the cloud function that produced them.
+    static ref COLLECTED: Mutex<HashMap<String, Vec<RecordBatch>>> = Mutex::new(HashMap::new());
+}
+
+/// A summary of the results routed by
+/// [`ExecutionContext::collect_into_sink`](crate::context::ExecutionContext::collect_into_sink).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SinkSummary {
+    /// The number of result batches.
+    pub num_batches: usize,
+    /// The number of result rows.
+    pub num_rows:    usize,
+    /// Where the results were routed to.
+    pub destination: CloudFunction,
+    /// The retry budget of the function instance after the results were
+    /// routed.
+    pub retries:     RetryStats,
+    /// The sizes of the payloads sent to the next functions, if any.
+    pub payload:     PayloadStats,
+}
+
+impl SinkSummary {
+    /// Returns the summary of the result batches routed to `destination`.
+    pub fn new(batches: &[RecordBatch], destination: &CloudFunction) -> Self {
+        Self {
+            num_batches: batches.len(),
+            num_rows:    batches.iter().map(|b| b.num_rows()).sum(),
+            destination: destination.clone(),
+            retries:     RetryStats::default(),
+            payload:     PayloadStats::default(),
+        }
+    }
+}
+
+/// A data sink for the final results of a query.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum DataSinkType {
+    /// Pretty-prints the result batches to stdout, using the same table format
+    /// as `assert_batches_eq!`. This sink is meant for local runs and CI.
+    Stdout,
+    /// Buffers the result batches in memory so that the local launcher can
+    /// retrieve them programmatically with [`DataSinkType::collected`].
+    Collect,
+}
+
+impl DataSinkType {
+    /// Parses a data sink type from its name, such as `stdout` or `collect`.
+    pub fn new(name: &str) -> Result<Self> {
+        name.parse()
+    }
+
+    /// Returns the data sink named by `name`, which overrides the `fallback`
+    /// sink. An empty or absent name doesn't override anything, but an
+    /// unknown name is an error rather than being silently ignored.
+    pub fn overridden(
+        name: Option<&str>,
+        fallback: Option<&DataSinkType>,
+    ) -> Result<Option<DataSinkType>> {
+        match name.map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            Some(name) => Ok(Some(name.parse()?)),
+            None => Ok(fallback.cloned()),
+        }
+    }
+
+    /// Writes the result batches of the cloud function `name` to the sink.
+    ///
+    /// Empty result sets are handled according to `emit_empty` in
+    /// `squirtle.toml`. See [`DataSinkType::write_results`].
+    pub fn write(&self, name: &str, batches: &[RecordBatch]) -> Result<()> {
+        let emit_empty = globals["lambda"]["emit_empty"].parse::<bool>().unwrap();
+        self.write_results(name, batches, emit_empty)
+    }
+
+    /// Writes the result batches of the cloud function `name` to the sink.
+    ///
+    /// A result set without any row (e.g. a window with no qualifying rows) is
+    /// only written if `emit_empty` is set, so that downstream consumers can
+    /// tell "no data" from "the function didn't run":
+    ///
+    /// - `Stdout` prints an empty table, i.e., the header only if the schema is
+    ///   known.
+    /// - `Collect` records `name` with no batches, which
+    ///   [`DataSinkType::has_collected`] reports.
+    pub fn write_results(
+        &self,
+        name: &str,
+        batches: &[RecordBatch],
+        emit_empty: bool,
+    ) -> Result<()> {
+        if batches.iter().all(|b| b.num_rows() == 0) && !emit_empty {
+            return Ok(());
+        }
+
+        match self {
+            DataSinkType::Stdout => {
+                println!("{}", arrow::util::pretty::pretty_format_batches(batches)?);
+            }
+            DataSinkType::Collect => {
+                COLLECTED
+                    .lock()
+                    .map_err(|e| SquirtleError::Internal(e.to_string()))?
+                    .entry(name.to_owned())
+                    .or_insert_with(Vec::new)
+                    .extend_from_slice(batches);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the record batches buffered by [`DataSinkType::Collect`] for the
+    /// cloud function `name`. Returns an empty vector if nothing was collected.
+    pub fn collected(name: &str) -> Vec<RecordBatch> {
+        COLLECTED.lock().unwrap().remove(name).unwrap_or_default()
+    }
+
+    /// Returns true if [`DataSinkType::Collect`] has recorded results for the
+    /// cloud function `name`, even an empty result set.
+    pub fn has_collected(name: &str) -> bool {
+        COLLECTED.lock().unwrap().contains_key(name)
+    }
+}
+
+/// What happens to the results produced before the execution of the plan
+/// fails, when the results go to a data sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OnError {
+    /// Drop the results, so that the sink gets all of the results or none.
+    Discard,
+    /// Write the results to the sink before failing, so that the sink gets
+    /// every result at least once if the invocation is retried.
+    FlushRemaining,
+}
+
+impl Default for OnError {
+    fn default() -> Self {
+        OnError::Discard
+    }
+}
+
+impl FromStr for OnError {
+    type Err = SquirtleError;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy.trim().to_lowercase().as_str() {
+            "discard" => Ok(OnError::Discard),
+            "flush" | "flush_remaining" => Ok(OnError::FlushRemaining),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown on-error policy: {}",
+                policy
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DataSinkType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataSinkType::Stdout => write!(f, "stdout"),
+            DataSinkType::Collect => write!(f, "collect"),
+        }
+    }
+}
+
+impl FromStr for DataSinkType {
+    type Err = SquirtleError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "stdout" => Ok(DataSinkType::Stdout),
+            "collect" => Ok(DataSinkType::Collect),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown data sink type: {}",
+                name
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int32Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    fn batch() -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )?)
+    }
+
+    #[test]
+    fn parse_sink_type() -> Result<()> {
+        assert_eq!(DataSinkType::Stdout, DataSinkType::new("stdout")?);
+        assert_eq!(DataSinkType::Collect, DataSinkType::new(" Collect ")?);
+        assert!(DataSinkType::new("s3").is_err());
+
+        for sink in &[DataSinkType::Stdout, DataSinkType::Collect] {
+            assert_eq!(*sink, DataSinkType::new(&sink.to_string())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_on_error() -> Result<()> {
+        assert_eq!(OnError::Discard, OnError::default());
+        assert_eq!(OnError::Discard, "discard".parse()?);
+        assert_eq!(OnError::FlushRemaining, " Flush_Remaining ".parse()?);
+        assert_eq!(OnError::FlushRemaining, "flush".parse()?);
+        assert!("retry".parse::<OnError>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn override_sink() -> Result<()> {
+        let stdout = DataSinkType::Stdout;
+
+        // The override takes precedence over the sink in the context.
+        assert_eq!(
+            Some(DataSinkType::Collect),
+            DataSinkType::overridden(Some("collect"), Some(&stdout))?
+        );
+        assert_eq!(
+            Some(DataSinkType::Collect),
+            DataSinkType::overridden(Some("collect"), None)?
+        );
+
+        // An absent or empty override falls back to the sink in the context.
+        assert_eq!(
+            Some(stdout.clone()),
+            DataSinkType::overridden(None, Some(&stdout))?
+        );
+        assert_eq!(
+            Some(stdout.clone()),
+            DataSinkType::overridden(Some(" "), Some(&stdout))?
+        );
+        assert_eq!(None, DataSinkType::overridden(None, None)?);
+
+        // An unknown override is rejected.
+        assert!(DataSinkType::overridden(Some("s3"), Some(&stdout)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn collect_sink() -> Result<()> {
+        let name = "collect_sink";
+        let sink = DataSinkType::Collect;
+        sink.write(name, &[batch()?])?;
+        sink.write(name, &[batch()?, batch()?])?;
+
+        let batches = DataSinkType::collected(name);
+        assert_eq!(3, batches.len());
+        assert!(DataSinkType::collected(name).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_results() -> Result<()> {
+        let empty = RecordBatch::new_empty(batch()?.schema());
+
+        for results in &[vec![], vec![empty]] {
+            // Nothing is written without `emit_empty`.
+            let name = "empty_results_skipped";
+            DataSinkType::Stdout.write_results(name, results, false)?;
+            DataSinkType::Collect.write_results(name, results, false)?;
+            assert!(!DataSinkType::has_collected(name));
+
+            // The empty result set is recorded with `emit_empty`.
+            let name = "empty_results_emitted";
+            DataSinkType::Stdout.write_results(name, results, true)?;
+            DataSinkType::Collect.write_results(name, results, true)?;
+            assert!(DataSinkType::has_collected(name));
+            assert_eq!(
+                0,
+                DataSinkType::collected(name)
+                    .iter()
+                    .map(|b| b.num_rows())
+                    .sum::<usize>()
+            );
+            assert!(!DataSinkType::has_collected(name));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stdout_sink() -> Result<()> {
+        DataSinkType::Stdout.write("stdout_sink", &[batch()?])?;
+        assert!(DataSinkType::collected("stdout_sink").is_empty());
+        Ok(())
+    }
+}
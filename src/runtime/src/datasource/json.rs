@@ -0,0 +1,265 @@
+// Copyright (c) 2021 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Newline-delimited JSON events with a user-supplied schema, for event types
+//! beyond the built-in ones.
+//!
+//! Each line is coerced to the schema before it is decoded:
+//!
+//! - a numeric string is read as a number for the numeric fields, e.g. `"42"`;
+//! - a number or a boolean is read as its string for the `Utf8` fields.
+//!
+//! A line that isn't a JSON object, has a value that can't be coerced, or lacks
+//! a non-nullable field is malformed, and is handled according to
+//! [`Malformed`].
+
+use crate::error::{Result, SquirtleError};
+use arrow::datatypes::{DataType, Schema, SchemaRef};
+use arrow::json::reader::Decoder;
+use arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::sync::Arc;
+
+/// The default number of rows per record batch, the same as the default batch
+/// size of Arrow's JSON reader.
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// What happens to the malformed lines of the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Malformed {
+    /// Fail the whole input.
+    Error,
+    /// Skip the malformed lines and count them.
+    Skip,
+}
+
+impl Default for Malformed {
+    fn default() -> Self {
+        Malformed::Error
+    }
+}
+
+/// A source of newline-delimited JSON events.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct JsonSource {
+    /// The schema of the events.
+    pub schema:    Schema,
+    /// What happens to the malformed lines.
+    #[serde(default)]
+    pub malformed: Malformed,
+}
+
+impl JsonSource {
+    /// Parses the events. Returns the record batches and the number of
+    /// skipped lines.
+    pub fn to_batch(&self, ndjson: &[u8]) -> Result<(Vec<RecordBatch>, usize)> {
+        to_batch_with(
+            ndjson,
+            Arc::new(self.schema.clone()),
+            DEFAULT_BATCH_SIZE,
+            self.malformed,
+        )
+    }
+}
+
+/// Parses newline-delimited JSON into record batches of `schema`. A malformed
+/// line is an error.
+pub fn to_batch(ndjson: &[u8], schema: SchemaRef) -> Result<Vec<RecordBatch>> {
+    Ok(to_batch_with(ndjson, schema, DEFAULT_BATCH_SIZE, Malformed::Error)?.0)
+}
+
+/// Parses newline-delimited JSON into record batches of `schema` with up to
+/// `batch_size` rows each. Returns the record batches and the number of
+/// malformed lines skipped. Blank lines are ignored.
+pub fn to_batch_with(
+    ndjson: &[u8],
+    schema: SchemaRef,
+    batch_size: usize,
+    malformed: Malformed,
+) -> Result<(Vec<RecordBatch>, usize)> {
+    let mut values = vec![];
+    let mut skipped = 0;
+    for (i, line) in ndjson.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        match serde_json::from_slice(line)
+            .ok()
+            .and_then(|value| coerce(value, &schema))
+        {
+            Some(value) => values.push(value),
+            None if malformed == Malformed::Skip => skipped += 1,
+            None => {
+                return Err(SquirtleError::Execution(format!(
+                    "Malformed JSON event at line {}: {}",
+                    i + 1,
+                    String::from_utf8_lossy(line)
+                )))
+            }
+        }
+    }
+
+    let decoder = Decoder::new(schema, batch_size, None);
+    let mut values = values.into_iter().map(Ok);
+    let mut batches = vec![];
+    while let Some(batch) = decoder.next_batch(&mut values)? {
+        batches.push(batch);
+    }
+    Ok((batches, skipped))
+}
+
+/// Coerces the fields of a JSON object to the types of `schema`. Returns
+/// `None` if the value is malformed.
+fn coerce(value: Value, schema: &Schema) -> Option<Value> {
+    let mut object = match value {
+        Value::Object(object) => object,
+        _ => return None,
+    };
+    let mut coerced = Map::new();
+    for field in schema.fields() {
+        let value = match object.remove(field.name()) {
+            Some(Value::Null) | None if field.is_nullable() => Value::Null,
+            Some(Value::Null) | None => return None,
+            Some(value) => coerce_value(value, field.data_type())?,
+        };
+        coerced.insert(field.name().to_owned(), value);
+    }
+    Some(Value::Object(coerced))
+}
+
+fn coerce_value(value: Value, data_type: &DataType) -> Option<Value> {
+    match (data_type, value) {
+        (DataType::Utf8, Value::String(s)) => Some(Value::String(s)),
+        (DataType::Utf8, Value::Number(n)) => Some(Value::String(n.to_string())),
+        (DataType::Utf8, Value::Bool(b)) => Some(Value::String(b.to_string())),
+        (DataType::Boolean, Value::Bool(b)) => Some(Value::Bool(b)),
+        (DataType::Boolean, Value::String(s)) => s.parse::<bool>().ok().map(Value::Bool),
+        (DataType::Float32, value) | (DataType::Float64, value) => match value {
+            Value::Number(n) => Some(Value::Number(n)),
+            Value::String(s) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .map(Value::Number),
+            _ => None,
+        },
+        (data_type, value) if is_integer(data_type) => match value {
+            Value::Number(n) if n.is_i64() || n.is_u64() => Some(Value::Number(n)),
+            Value::String(s) => s
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .map(|n| Value::Number(n.into())),
+            _ => None,
+        },
+        // other types, such as lists, are left to Arrow's JSON reader
+        (_, value) => Some(value),
+    }
+}
+
+fn is_integer(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+            | DataType::Date32
+            | DataType::Date64
+            | DataType::Timestamp(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, Float64Array, Int64Array, StringArray};
+    use arrow::datatypes::Field;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+            Field::new("price", DataType::Float64, true),
+        ]))
+    }
+
+    #[test]
+    fn ndjson_to_batch() -> Result<()> {
+        let events = br#"{"id": 1, "name": "apple", "price": 1.5}
+{"id": "2", "name": 42, "price": "2.25"}
+
+{"id": 3}
+"#;
+        let batches = to_batch(events, schema())?;
+        assert_eq!(1, batches.len());
+        let batch = &batches[0];
+        assert_eq!(3, batch.num_rows());
+
+        let ids = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], ids.values().to_vec());
+        let names = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!("42", names.value(1));
+        assert!(names.is_null(2));
+        let prices = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert!((prices.value(1) - 2.25).abs() < f64::EPSILON);
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_lines() -> Result<()> {
+        let events = br#"{"id": 1, "name": "apple"}
+{"id": 2, "name": "banana
+{"id": "three"}
+{"name": "no id"}
+[4]
+{"id": 5}"#;
+        assert!(to_batch(events, schema()).is_err());
+
+        let (batches, skipped) = to_batch_with(events, schema(), 1, Malformed::Skip)?;
+        assert_eq!(4, skipped);
+        assert_eq!(2, batches.len());
+        let ids = batches
+            .iter()
+            .map(|b| {
+                b.column(0)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap()
+                    .value(0)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 5], ids);
+
+        Ok(())
+    }
+}
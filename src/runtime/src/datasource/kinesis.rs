@@ -23,7 +23,6 @@ use arrow::record_batch::RecordBatch;
 use crate::error::Result;
 use crate::query::StreamWindow;
 use rayon::prelude::*;
-use rusoto_core::Region;
 use rusoto_kinesis::{DescribeStreamInput, Kinesis, KinesisClient};
 use rusoto_lambda::CreateEventSourceMappingRequest;
 use serde::{Deserialize, Serialize};
@@ -52,7 +51,7 @@ pub async fn create_event_source_mapping_request(
     function_name: &str,
     window_in_seconds: i64,
 ) -> Result<CreateEventSourceMappingRequest> {
-    let client = KinesisClient::new(Region::default());
+    let client = KinesisClient::new(crate::config::aws_region());
     let output = client
         .describe_stream(DescribeStreamInput {
             stream_name: stream_name.to_string(),
@@ -14,6 +14,7 @@
 
 //! A data source is the location where data that is being used originates from.
 
+use json::JsonSource;
 use kafka::KafkaSource;
 use kinesis::KinesisSource;
 use nexmark::NexMarkSource;
@@ -41,6 +42,8 @@ pub enum DataSource {
     /// work. Using SQS, you can send, store, and receive messages between
     /// software components at any volume, without losing messages or requiring
     /// other services to be available.
+    ///
+    /// The message bodies are JSON events, see [`sqs::to_batch`].
     SqsEvent,
     /// Amazon Simple Notification Service (Amazon SNS) is a fully managed
     /// messaging service for both application-to-application (A2A) and
@@ -57,6 +60,8 @@ pub enum DataSource {
     /// - 256 KB (asynchronous)
     /// <https://docs.aws.amazon.com/lambda/latest/dg/gettingstarted-limits.html>
     Payload,
+    /// Newline-delimited JSON events with a user-supplied schema.
+    NdJson(JsonSource),
     /// Data source for unit tests.
     Json,
     /// Unknown data source.
@@ -76,6 +81,8 @@ impl DataSource {
     }
 }
 
+pub mod json;
 pub mod kafka;
 pub mod kinesis;
 pub mod nexmark;
+pub mod sqs;
@@ -73,4 +73,7 @@ pub mod nexmark;
 
 mod queries;
 
-pub use nexmark::{NexMarkEvent, NexMarkSource, NexMarkStream};
+pub use nexmark::{
+    assign_generators, query_streams, EventStream, NexMarkEvent, NexMarkSource, NexMarkStream,
+    DEFAULT_BATCH_SIZE, EXECUTABLE_QUERIES,
+};
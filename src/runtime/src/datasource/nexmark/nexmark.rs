@@ -22,14 +22,16 @@ use arrow::record_batch::RecordBatch;
 use log::info;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 use std::io::BufReader;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 use crate::datasource::nexmark::config::{Config, NEXMarkConfig};
 use crate::datasource::nexmark::event::{Auction, Bid, Date, Person};
 use crate::datasource::nexmark::generator::NEXMarkGenerator;
-use crate::error::Result;
+use crate::error::{Result, SquirtleError};
 use crate::query::StreamWindow;
 
 type Epoch = Date;
@@ -63,6 +65,109 @@ pub struct NexMarkEvent {
     pub epoch:    usize,
     /// The data source identifier.
     pub source:   usize,
+    /// The only stream of the events, if the event is sent to the source
+    /// function of that stream. `None` if it carries all three streams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream:   Option<EventStream>,
+}
+
+/// One of the three streams of NEXMark events.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, Abomonation,
+)]
+pub enum EventStream {
+    /// The Person events.
+    Person,
+    /// The Auction events.
+    Auction,
+    /// The Bid events.
+    Bid,
+}
+
+impl FromStr for EventStream {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "person" | "persons" => Ok(EventStream::Person),
+            "auction" | "auctions" => Ok(EventStream::Auction),
+            "bid" | "bids" => Ok(EventStream::Bid),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown NEXMark stream: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventStream::Person => write!(f, "person"),
+            EventStream::Auction => write!(f, "auction"),
+            EventStream::Bid => write!(f, "bid"),
+        }
+    }
+}
+
+/// Assigns each of the `generators` to one of the streams, in proportion to
+/// the ratio of the stream. The generators of a stream are consecutive, in the
+/// order of `ratios`. The generators left over by the rounding go to the
+/// streams with the largest remainders, and every stream gets at least one
+/// generator.
+///
+/// Returns the stream of each generator, or an error if a stream is listed
+/// twice, has a zero ratio, or there are fewer generators than streams.
+pub fn assign_generators(
+    generators: usize,
+    ratios: &[(EventStream, usize)],
+) -> Result<Vec<EventStream>> {
+    for (i, (stream, ratio)) in ratios.iter().enumerate() {
+        if *ratio == 0 {
+            return Err(SquirtleError::Internal(format!(
+                "The ratio of the {} stream must be positive.",
+                stream
+            )));
+        }
+        if ratios[..i].iter().any(|(s, _)| s == stream) {
+            return Err(SquirtleError::Internal(format!(
+                "The {} stream is listed twice.",
+                stream
+            )));
+        }
+    }
+    if ratios.is_empty() || generators < ratios.len() {
+        return Err(SquirtleError::Internal(format!(
+            "{} generators can't feed {} streams.",
+            generators,
+            ratios.len()
+        )));
+    }
+
+    let total: usize = ratios.iter().map(|(_, ratio)| ratio).sum();
+    let mut shares: Vec<usize> = ratios
+        .iter()
+        .map(|(_, ratio)| generators * ratio / total)
+        .collect();
+    let mut by_remainder: Vec<usize> = (0..ratios.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(generators * ratios[i].1 % total));
+    let left = generators - shares.iter().sum::<usize>();
+    for &i in by_remainder.iter().take(left) {
+        shares[i] += 1;
+    }
+
+    // A stream with a small ratio takes a generator from the largest share.
+    while let Some(i) = shares.iter().position(|&share| share == 0) {
+        let largest = (0..shares.len()).max_by_key(|&j| shares[j]).unwrap();
+        shares[largest] -= 1;
+        shares[i] += 1;
+    }
+
+    Ok(ratios
+        .iter()
+        .zip(shares)
+        .flat_map(|((stream, _), share)| std::iter::repeat(*stream).take(share))
+        .collect())
 }
 
 impl NexMarkStream {
@@ -108,6 +213,79 @@ impl NexMarkStream {
 
         Some(event)
     }
+
+    /// Fetches the events of a single stream that belong to the given epoch
+    /// and source identifier.
+    pub fn select_stream(
+        &self,
+        time: usize,
+        source: usize,
+        stream: EventStream,
+    ) -> Option<NexMarkEvent> {
+        let mut event = self.select(time, source)?;
+        match stream {
+            EventStream::Person => {
+                event.auctions.clear();
+                event.bids.clear();
+            }
+            EventStream::Auction => {
+                event.persons.clear();
+                event.bids.clear();
+            }
+            EventStream::Bid => {
+                event.persons.clear();
+                event.auctions.clear();
+            }
+        }
+        if event.persons.is_empty() && event.auctions.is_empty() && event.bids.is_empty() {
+            return None;
+        }
+        event.stream = Some(stream);
+        Some(event)
+    }
+
+    /// Returns the number of events of a single stream for a given epoch and
+    /// source identifier.
+    pub fn num_stream_events(&self, time: usize, source: usize, stream: EventStream) -> usize {
+        let events = match stream {
+            EventStream::Person => &self.persons,
+            EventStream::Auction => &self.auctions,
+            EventStream::Bid => &self.bids,
+        };
+        events
+            .get(&Epoch::new(time))
+            .and_then(|map| map.get(&source))
+            .map_or(0, |(_, num)| *num)
+    }
+
+    /// Returns the number of events of all types for a given epoch and source
+    /// identifier.
+    pub fn num_events(&self, time: usize, source: usize) -> usize {
+        let epoch = Epoch::new(time);
+        [&self.persons, &self.auctions, &self.bids]
+            .iter()
+            .filter_map(|events| events.get(&epoch).and_then(|map| map.get(&source)))
+            .map(|(_, num)| num)
+            .sum()
+    }
+}
+
+/// The default number of rows in each record batch converted from the events.
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// The NEXMark queries that the cloud function knows how to feed with events.
+pub const EXECUTABLE_QUERIES: std::ops::RangeInclusive<usize> = 0..=4;
+
+/// Returns the streams that an executable NEXMark query reads, in the order
+/// the cloud function feeds them to the plan, or no stream if the query isn't
+/// executable.
+pub fn query_streams(query: usize) -> Vec<EventStream> {
+    match query {
+        0..=2 => vec![EventStream::Bid],
+        3 => vec![EventStream::Person, EventStream::Auction],
+        4 => vec![EventStream::Auction, EventStream::Bid],
+        _ => vec![],
+    }
 }
 
 /// A struct to generate events for Nexmark benchmarks.
@@ -223,9 +401,24 @@ impl NexMarkSource {
         Ok(std::mem::take(&mut events))
     }
 
+    /// Returns the number of rows in each record batch converted from the
+    /// events, which is set by the `batch-size` key in the configuration.
+    pub fn batch_size(&self) -> usize {
+        self.config.get_as_or("batch-size", DEFAULT_BATCH_SIZE)
+    }
+
     /// Converts NexMarkSource events to record batches in Arrow.
     pub fn to_batch(events: &[u8], schema: SchemaRef) -> Vec<RecordBatch> {
-        let batch_size = 1024;
+        NexMarkSource::to_batch_with_size(events, schema, DEFAULT_BATCH_SIZE)
+    }
+
+    /// Converts NexMarkSource events to record batches of `batch_size` rows in
+    /// Arrow. The last batch may be smaller.
+    pub fn to_batch_with_size(
+        events: &[u8],
+        schema: SchemaRef,
+        batch_size: usize,
+    ) -> Vec<RecordBatch> {
         let mut reader = json::Reader::new(BufReader::new(events), schema, batch_size, None);
 
         let mut batches = vec![];
@@ -284,6 +477,13 @@ mod test {
         assert_eq!(events.auctions.len(), 1);
         assert_eq!(events.bids.len(), 1);
         assert_eq!(nex.count_events(&events), 10_000);
+        assert_eq!(
+            10_000,
+            (0..10)
+                .map(|source| events.num_events(0, source))
+                .sum::<usize>()
+        );
+        assert_eq!(0, events.num_events(1, 0));
 
         let seconds = 10;
         let threads = 100;
@@ -334,4 +534,64 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_nexmark_batch_size() -> Result<()> {
+        let mut nex = NexMarkSource::new(1, 1, 1000, StreamWindow::None);
+        assert_eq!(DEFAULT_BATCH_SIZE, nex.batch_size());
+        nex.config.insert("batch-size", 64.to_string());
+        assert_eq!(64, nex.batch_size());
+
+        let events = nex.generate_data()?;
+        let events = events.select(0, 0).unwrap();
+        let batches = NexMarkSource::to_batch_with_size(&events.bids, Arc::new(Bid::schema()), 64);
+        let num_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert!(num_rows > 64);
+        assert_eq!((num_rows + 63) / 64, batches.len());
+        assert!(batches[..batches.len() - 1]
+            .iter()
+            .all(|b| b.num_rows() == 64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn generators_per_stream() -> Result<()> {
+        let ratios = [(EventStream::Bid, 3), (EventStream::Auction, 1)];
+        let streams = assign_generators(10, &ratios)?;
+        assert_eq!(10, streams.len());
+        assert_eq!(vec![EventStream::Bid; 8], streams[..8].to_vec());
+        assert_eq!(vec![EventStream::Auction; 2], streams[8..].to_vec());
+        assert_eq!(
+            vec![EventStream::Bid, EventStream::Auction],
+            assign_generators(2, &ratios)?
+        );
+
+        assert!(assign_generators(1, &ratios).is_err());
+        assert!(assign_generators(10, &[]).is_err());
+        assert!(assign_generators(10, &[(EventStream::Bid, 0)]).is_err());
+        assert!(assign_generators(10, &[(EventStream::Bid, 1), (EventStream::Bid, 1)]).is_err());
+
+        // Each stream's source function gets the events of its stream only.
+        let nex = NexMarkSource::new(1, 2, 1_000, StreamWindow::None);
+        let events = nex.generate_data()?;
+        for (g, stream) in assign_generators(2, &ratios)?.into_iter().enumerate() {
+            let event = events.select_stream(0, g, stream).unwrap();
+            assert_eq!(Some(stream), event.stream);
+            assert_eq!(stream == EventStream::Bid, !event.bids.is_empty());
+            assert!(event.persons.is_empty());
+            assert!(events.num_stream_events(0, g, stream) > 0);
+        }
+        assert_eq!("bid", EventStream::Bid.to_string());
+        assert_eq!(EventStream::Person, "persons".parse()?);
+
+        for q in EXECUTABLE_QUERIES {
+            assert!(!query_streams(q).is_empty());
+        }
+        assert_eq!(vec![EventStream::Bid], query_streams(0));
+        assert_eq!(2, query_streams(4).len());
+        assert!(query_streams(5).is_empty());
+
+        Ok(())
+    }
 }
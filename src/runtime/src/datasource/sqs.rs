@@ -11,3 +11,79 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 // Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! Amazon Simple Queue Service (SQS) messages with JSON bodies, for the
+//! low-volume pipelines that trigger the functions through a queue rather
+//! than a stream.
+
+use aws_lambda_events::event::sqs::SqsEvent;
+
+use arrow::json::reader::infer_json_schema;
+use arrow::record_batch::RecordBatch;
+
+use crate::datasource::json;
+use crate::error::{Result, SquirtleError};
+use serde_json::Value;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// Converts the JSON bodies of the SQS messages to record batches in Arrow.
+///
+/// The schema is inferred from the first message with a body. Messages
+/// without a body are skipped, and a body that isn't JSON is an error. Each
+/// body is compacted to a single line, so pretty-printed bodies are fine.
+pub fn to_batch(event: SqsEvent) -> Result<Vec<RecordBatch>> {
+    let mut input = vec![];
+    for message in event.records {
+        let body = match message.body {
+            Some(body) => body,
+            None => continue,
+        };
+        let value: Value = serde_json::from_str(&body).map_err(|e| {
+            SquirtleError::Execution(format!(
+                "The body of the SQS message {} isn't JSON: {}",
+                message.message_id.unwrap_or_default(),
+                e
+            ))
+        })?;
+        serde_json::to_writer(&mut input, &value)?;
+        input.push(b'\n');
+    }
+    if input.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // infer schema based on the first record
+    let schema = infer_json_schema(&mut BufReader::new(&input[..]), Some(1))?;
+    json::to_batch(&input, Arc::new(schema))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use arrow::array::Int64Array;
+
+    #[test]
+    fn example_sqs_event() -> Result<()> {
+        let data = include_bytes!("../../../test/data/example-sqs-event.json");
+        let parsed: SqsEvent = serde_json::from_slice(data)?;
+        let batches = to_batch(parsed.clone())?;
+        assert_eq!(1, batches.len());
+        assert_eq!(2, batches[0].num_rows());
+
+        let schema = batches[0].schema();
+        let cust_ids = batches[0]
+            .column(schema.index_of("cust_id")?)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(vec![123, 124], cust_ids.values().to_vec());
+
+        // A body that isn't JSON fails the whole event.
+        let mut malformed = parsed;
+        malformed.records[1].body = Some("test".to_owned());
+        assert!(to_batch(malformed).is_err());
+
+        Ok(())
+    }
+}
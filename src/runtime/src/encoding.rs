@@ -16,7 +16,16 @@
 //! all environment variables so that they doesn't exceed 4 KB.
 
 use abomonation::{decode, encode};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// The default window size (log2 of bytes) used by Zstd long-distance
+/// matching. 2^27 bytes (128 MB) is the same window `zstd --long` picks, and
+/// it is also the largest window a decoder accepts without further settings.
+pub const ZSTD_LDM_WINDOW_LOG: u32 = 27;
 
 /// A compressor/decompressor type.
 #[derive(Debug, Clone, Abomonation, Deserialize, Serialize, PartialEq)]
@@ -35,8 +44,14 @@ pub enum Encoding {
     /// <https://github.com/bozaro/lz4-rs>
     Lz4,
     /// A streaming compression/decompression library DEFLATE-based streams.
+    /// The data is framed in the zlib format, which is the `deflate`
+    /// content-encoding of HTTP.
     /// <https://github.com/rust-lang/flate2-rs>
     Zlib,
+    /// DEFLATE-based streams framed in the gzip format, which is the `gzip`
+    /// content-encoding of HTTP.
+    /// <https://github.com/rust-lang/flate2-rs>
+    Gzip,
     /// A fast lossless compression algorithm, targeting real-time compression
     /// scenarios at zlib-level and better compression ratios. <https://github.com/facebook/zstd>
     Zstd,
@@ -59,9 +74,18 @@ impl Encoding {
                 encoder.compress_vec(s).unwrap()
             }
             Encoding::Lz4 => lz4::block::compress(s, None, true).unwrap(),
+            Encoding::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(s).unwrap();
+                encoder.finish().unwrap()
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(s).unwrap();
+                encoder.finish().unwrap()
+            }
             Encoding::Zstd => zstd::block::compress(s, 3).unwrap(),
             Encoding::None => s.into(),
-            _ => unimplemented!(),
         }
     }
 
@@ -77,10 +101,77 @@ impl Encoding {
                 s, 10485760, // The decompressed data should be less than 10 MB
             )
             .unwrap(),
+            Encoding::Zlib => {
+                let mut buf = Vec::new();
+                ZlibDecoder::new(s).read_to_end(&mut buf).unwrap();
+                buf
+            }
+            Encoding::Gzip => {
+                let mut buf = Vec::new();
+                GzDecoder::new(s).read_to_end(&mut buf).unwrap();
+                buf
+            }
             Encoding::None => s.into(),
-            _ => {
-                unimplemented!();
+        }
+    }
+
+    /// Returns the encoding for the value of an HTTP `Content-Encoding`
+    /// header, such as `gzip` or `deflate`, so that payloads coming through a
+    /// gateway that applies a content-encoding are decompressed accordingly.
+    /// Returns `None` for unsupported content-encodings.
+    pub fn from_content_encoding(value: &str) -> Option<Encoding> {
+        match value.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Zlib),
+            "identity" | "" => Some(Encoding::None),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the HTTP `Content-Encoding` header matching the
+    /// encoding, or `None` if the encoding has no standard counterpart.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match *self {
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Zlib => Some("deflate"),
+            Encoding::None => Some("identity"),
+            _ => None,
+        }
+    }
+
+    /// Compress data with Zstd long-distance matching (LDM) enabled.
+    ///
+    /// The default Zstd window misses redundancy that is far apart in the
+    /// input, such as the repeated subtrees of a self-join plan. LDM searches
+    /// a much larger window (`2^window_log` bytes) for those matches. Other
+    /// encodings ignore `window_log` and fall back to [`Encoding::compress`].
+    pub fn compress_ldm(&self, s: &[u8], window_log: u32) -> Vec<u8> {
+        match *self {
+            Encoding::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+                encoder.long_distance_matching(true).unwrap();
+                encoder.window_log(window_log).unwrap();
+                encoder.write_all(s).unwrap();
+                encoder.finish().unwrap()
             }
+            _ => self.compress(s),
+        }
+    }
+
+    /// Decompress data produced by [`Encoding::compress_ldm`].
+    ///
+    /// `window_log` must be the value used by the compressor, otherwise the
+    /// decoder refuses frames whose window exceeds its limit.
+    pub fn decompress_ldm(&self, s: &[u8], window_log: u32) -> Vec<u8> {
+        match *self {
+            Encoding::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::new(s).unwrap();
+                decoder.window_log_max(window_log).unwrap();
+                let mut buf = Vec::new();
+                decoder.read_to_end(&mut buf).unwrap();
+                buf
+            }
+            _ => self.decompress(s),
         }
     }
 }
@@ -174,4 +265,65 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn content_encoding() {
+        let data = b"SELECT auction, price FROM bid WHERE auction % 123 = 0".repeat(16);
+        for en in &[Encoding::Gzip, Encoding::Zlib, Encoding::None] {
+            let value = en.content_encoding().unwrap();
+            assert_eq!(Some(en.clone()), Encoding::from_content_encoding(value));
+            assert_eq!(data, en.decompress(&en.compress(&data)));
+        }
+
+        // The gzip encoding interoperates with the standard gzip format.
+        let gzip = Encoding::Gzip.compress(&data);
+        assert_eq!(&[0x1f, 0x8b], &gzip[..2]);
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(data, decoded);
+
+        assert_eq!(
+            Some(Encoding::Gzip),
+            Encoding::from_content_encoding(" GZIP ")
+        );
+        assert_eq!(None, Encoding::from_content_encoding("br"));
+        assert_eq!(None, Encoding::Lz4.content_encoding());
+    }
+
+    #[tokio::test]
+    async fn zstd_long_distance_matching() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        // A 3 MB random chunk repeated three times. The repeats are further apart
+        // than the default Zstd window at level 3 (2 MB), so only LDM can find
+        // them.
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(42);
+        let chunk: Vec<u8> = (0..3 * 1024 * 1024).map(|_| rng.gen::<u8>()).collect();
+        let data = chunk.repeat(3);
+
+        let en = Encoding::Zstd;
+        let plain = en.compress(&data);
+        let ldm = en.compress_ldm(&data, ZSTD_LDM_WINDOW_LOG);
+        println!(
+            "Repetitive buffer: {} bytes, zstd: {} bytes, zstd + ldm: {} bytes",
+            data.len(),
+            plain.len(),
+            ldm.len()
+        );
+        assert!(ldm.len() < plain.len());
+
+        let de = en.decompress_ldm(&ldm, ZSTD_LDM_WINDOW_LOG);
+        assert_eq!(data, de);
+
+        // Non-zstd encodings ignore the window and round trip as usual.
+        let lz4 = Encoding::Lz4.compress_ldm(&data, ZSTD_LDM_WINDOW_LOG);
+        assert_eq!(
+            data,
+            Encoding::Lz4.decompress_ldm(&lz4, ZSTD_LDM_WINDOW_LOG)
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,395 @@
+// Copyright (c) 2020 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+// Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! The hash functions that map the shuffle keys of a row to a member of the
+//! next function group.
+//!
+//! By default, the keys are hashed with BLAKE2b, like the names of the
+//! functions, and DataFusion's hash partitioning of the key columns is also
+//! available. To co-partition with an external system, such as a Kafka
+//! producer, the keys can be hashed the same way the external system does
+//! instead. Each key column is hashed as its display string, so a string key
+//! is hashed as its UTF-8 bytes, just like a Kafka string key. The strings of
+//! several key columns are concatenated.
+//!
+//! Changing the hash function of a deployed query changes the member that owns
+//! each key, so any per-key state kept by the members is lost.
+//!
+//! Rows that can't be routed to their member, i.e., the rows with a null key
+//! and the buckets of members that don't exist, are handled according to the
+//! [`Unroutable`] policy.
+
+use crate::error::{Result, SquirtleError};
+use arrow::array::{BooleanArray, UInt32Array};
+use arrow::compute::{and, filter_record_batch, is_not_null, not, take};
+use arrow::error::Result as ArrowResult;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use blake2::{Blake2b, Digest};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The hash function of the shuffle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ShuffleHash {
+    /// DataFusion's hash partitioning.
+    DataFusion,
+    /// The first 8 bytes of the BLAKE2b digest of the key, in little endian.
+    Blake2b,
+    /// Kafka's default partitioner: the positive murmur2 hash of the key.
+    Murmur2,
+}
+
+impl Default for ShuffleHash {
+    fn default() -> Self {
+        ShuffleHash::Blake2b
+    }
+}
+
+impl FromStr for ShuffleHash {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "datafusion" => Ok(ShuffleHash::DataFusion),
+            "blake2b" => Ok(ShuffleHash::Blake2b),
+            "murmur2" => Ok(ShuffleHash::Murmur2),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown shuffle hash: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl ShuffleHash {
+    /// Returns the member of a function group of `group_size` that owns `key`.
+    ///
+    /// Returns `None` for [`ShuffleHash::DataFusion`], which hashes the key
+    /// columns directly rather than their bytes. See
+    /// [`LambdaExecutor::shuffle`](super::LambdaExecutor::shuffle).
+    pub fn bucket(&self, key: &[u8], group_size: usize) -> Option<usize> {
+        match self {
+            ShuffleHash::DataFusion => None,
+            ShuffleHash::Blake2b => {
+                let digest = Blake2b::digest(key);
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&digest[..8]);
+                Some((u64::from_le_bytes(bytes) % group_size as u64) as usize)
+            }
+            ShuffleHash::Murmur2 => Some(((murmur2(key) & 0x7fff_ffff) as usize) % group_size),
+        }
+    }
+
+    /// Splits the batches into `group_size` buckets by the bucket of the
+    /// `keys` of each row.
+    pub fn shuffle(
+        &self,
+        batches: &[RecordBatch],
+        keys: &[String],
+        group_size: usize,
+    ) -> Result<Vec<Vec<RecordBatch>>> {
+        let mut buckets = vec![vec![]; group_size];
+        for batch in batches {
+            let columns = keys
+                .iter()
+                .map(|key| Ok(batch.column(batch.schema().index_of(key)?).clone()))
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut rows = vec![vec![]; group_size];
+            let mut key = vec![];
+            for row in 0..batch.num_rows() {
+                key.clear();
+                for column in &columns {
+                    key.extend_from_slice(array_value_to_string(column, row)?.as_bytes());
+                }
+                let bucket = self.bucket(&key, group_size).ok_or_else(|| {
+                    SquirtleError::Internal(format!("{:?} doesn't hash the bytes of a key.", self))
+                })?;
+                rows[bucket].push(row as u32);
+            }
+
+            for (bucket, rows) in buckets.iter_mut().zip(rows) {
+                if rows.is_empty() {
+                    continue;
+                }
+                let indices = UInt32Array::from(rows);
+                let columns = batch
+                    .columns()
+                    .iter()
+                    .map(|column| take(column.as_ref(), &indices, None))
+                    .collect::<ArrowResult<Vec<_>>>()?;
+                bucket.push(RecordBatch::try_new(batch.schema(), columns)?);
+            }
+        }
+        Ok(buckets)
+    }
+}
+
+/// What happens to the shuffled rows that can't be routed to their member of
+/// the next function group: the rows with a null key, and the buckets of
+/// members that don't exist, e.g. because the group shrunk after the query was
+/// deployed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Unroutable {
+    /// Fail the invocation.
+    ErrorOnUnroutable,
+    /// Route the rows to the first member of the group.
+    RouteToZero,
+    /// Write the rows to the dead-letter sink, `dead_letter_sink` in
+    /// `squirtle.toml`.
+    DeadLetter,
+}
+
+impl Default for Unroutable {
+    fn default() -> Self {
+        Unroutable::RouteToZero
+    }
+}
+
+impl FromStr for Unroutable {
+    type Err = SquirtleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "error" | "error_on_unroutable" => Ok(Unroutable::ErrorOnUnroutable),
+            "zero" | "route_to_zero" => Ok(Unroutable::RouteToZero),
+            "dead_letter" => Ok(Unroutable::DeadLetter),
+            _ => Err(SquirtleError::Internal(format!(
+                "Unknown unroutable policy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Unroutable {
+    /// Applies the policy to the `rows` that can't be routed to their member,
+    /// because of `reason`. The rows routed to the first member are appended
+    /// to `buckets[0]`. Returns the dead letters.
+    pub fn reroute(
+        &self,
+        rows: Vec<RecordBatch>,
+        buckets: &mut [Vec<RecordBatch>],
+        reason: &str,
+    ) -> Result<Vec<RecordBatch>> {
+        let num_rows: usize = rows.iter().map(|b| b.num_rows()).sum();
+        if num_rows == 0 {
+            return Ok(vec![]);
+        }
+        match self {
+            Unroutable::ErrorOnUnroutable => Err(SquirtleError::Execution(format!(
+                "{} rows can't be routed: {}",
+                num_rows, reason
+            ))),
+            Unroutable::RouteToZero => {
+                buckets[0].extend(rows);
+                Ok(vec![])
+            }
+            Unroutable::DeadLetter => Ok(rows),
+        }
+    }
+}
+
+/// Splits the rows of the batches into the rows whose `keys` are all non-null
+/// and the rows with a null key, which have no member to be routed to.
+pub fn split_null_keys(
+    batches: Vec<RecordBatch>,
+    keys: &[String],
+) -> Result<(Vec<RecordBatch>, Vec<RecordBatch>)> {
+    let mut routable = vec![];
+    let mut null_keys = vec![];
+    for batch in batches {
+        let mut valid: Option<BooleanArray> = None;
+        for key in keys {
+            let column = batch.column(batch.schema().index_of(key)?);
+            if column.null_count() == 0 {
+                continue;
+            }
+            let not_null = is_not_null(column.as_ref())?;
+            valid = Some(match valid {
+                Some(valid) => and(&valid, &not_null)?,
+                None => not_null,
+            });
+        }
+        match valid {
+            Some(valid) => {
+                routable.push(filter_record_batch(&batch, &valid)?);
+                null_keys.push(filter_record_batch(&batch, &not(&valid)?)?);
+            }
+            None => routable.push(batch),
+        }
+    }
+    Ok((routable, null_keys))
+}
+
+/// The murmur2 hash of Kafka's default partitioner.
+/// <https://github.com/apache/kafka/blob/trunk/clients/src/main/java/org/apache/kafka/common/utils/Utils.java>
+pub fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let length = data.len();
+    let mut h = SEED ^ length as u32;
+    for chunk in data.chunks_exact(4) {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let tail = &data[length & !3..];
+    if tail.len() == 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::StringArray;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    #[test]
+    fn kafka_murmur2() {
+        // The test vectors of Kafka's `UtilsTest`.
+        assert_eq!(-973932308, murmur2(b"21"));
+        assert_eq!(-790332482, murmur2(b"foobar"));
+        assert_eq!(-985981536, murmur2(b"a-little-bit-long-string"));
+        assert_eq!(-1486304829, murmur2(b"a-little-bit-longer-string"));
+        assert_eq!(
+            -58897971,
+            murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8")
+        );
+        assert_eq!(479470107, murmur2(b"abc"));
+    }
+
+    #[test]
+    fn stable_but_different_mappings() -> Result<()> {
+        let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+        let schema = Arc::new(Schema::new(vec![Field::new("k", DataType::Utf8, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(
+                keys.iter().map(|k| k.as_str()).collect::<Vec<_>>(),
+            ))],
+        )?;
+
+        let owners = |hash: ShuffleHash| -> Result<Vec<usize>> {
+            let buckets = hash.shuffle(&[batch.clone()], &["k".to_owned()], 8)?;
+            let mut owners = vec![0; keys.len()];
+            for (i, bucket) in buckets.iter().enumerate() {
+                for b in bucket {
+                    let column = b.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+                    for row in 0..b.num_rows() {
+                        let key = keys.iter().position(|k| k == column.value(row)).unwrap();
+                        owners[key] = i;
+                    }
+                }
+            }
+            Ok(owners)
+        };
+
+        let by_blake2b = owners(ShuffleHash::Blake2b)?;
+        let by_murmur2 = owners(ShuffleHash::Murmur2)?;
+        assert_eq!(by_blake2b, owners(ShuffleHash::Blake2b)?);
+        assert_eq!(by_murmur2, owners(ShuffleHash::Murmur2)?);
+        assert_ne!(by_blake2b, by_murmur2);
+        assert_eq!(ShuffleHash::Blake2b, ShuffleHash::default());
+
+        // DataFusion's hash partitioning doesn't hash the bytes of a key.
+        assert_eq!(None, ShuffleHash::DataFusion.bucket(b"key-0", 8));
+        assert!(ShuffleHash::DataFusion
+            .shuffle(&[batch.clone()], &["k".to_owned()], 8)
+            .is_err());
+
+        // A string key is owned by the same member as its Kafka partition.
+        assert_eq!(
+            (murmur2(b"key-0") & 0x7fff_ffff) as usize % 8,
+            by_murmur2[0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unroutable_rows() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("k", DataType::Utf8, true)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(vec![
+                Some("a"),
+                None,
+                Some("b"),
+                None,
+            ]))],
+        )?;
+        let num_rows =
+            |batches: &[RecordBatch]| -> usize { batches.iter().map(|b| b.num_rows()).sum() };
+
+        // The rows with a null key are split off before the shuffle.
+        let (routable, null_keys) = split_null_keys(vec![batch], &["k".to_owned()])?;
+        assert_eq!(2, num_rows(&routable));
+        assert_eq!(2, num_rows(&null_keys));
+        assert_eq!(2, null_keys[0].column(0).null_count());
+
+        let mut buckets = ShuffleHash::Murmur2.shuffle(&routable, &["k".to_owned()], 4)?;
+        let zero = num_rows(&buckets[0]);
+        assert!(Unroutable::ErrorOnUnroutable
+            .reroute(null_keys.clone(), &mut buckets, "null keys")
+            .is_err());
+        assert_eq!(
+            2,
+            num_rows(&Unroutable::DeadLetter.reroute(
+                null_keys.clone(),
+                &mut buckets,
+                "null keys"
+            )?)
+        );
+        assert!(Unroutable::RouteToZero
+            .reroute(null_keys, &mut buckets, "null keys")?
+            .is_empty());
+        assert_eq!(zero + 2, num_rows(&buckets[0]));
+
+        // The buckets of the members beyond a shrunk group are rerouted.
+        let missing = buckets.split_off(2).concat();
+        let total = num_rows(&buckets.concat()) + num_rows(&missing);
+        Unroutable::RouteToZero.reroute(missing, &mut buckets, "shrunk group")?;
+        assert_eq!(total, num_rows(&buckets.concat()));
+        assert_eq!(4, total);
+
+        // Nothing to reroute is never an error.
+        assert!(Unroutable::ErrorOnUnroutable
+            .reroute(vec![], &mut buckets, "nothing")?
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -29,17 +29,25 @@ use crate::context::CloudFunction;
 use crate::context::ExecutionContext;
 use crate::encoding::Encoding;
 use crate::error::{Result, SquirtleError};
-use crate::payload::{Payload, Uuid};
+use crate::naming::group_member;
+use crate::payload::{Payload, PayloadStats, Uuid, UuidBuilder};
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
 use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::expressions::col;
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::repartition::RepartitionExec;
 use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+use futures::executor::block_on;
 use futures::stream::StreamExt;
+use hash::ShuffleHash;
+use log::warn;
 use plan::*;
 use rand::Rng;
 use rayon::prelude::*;
+use retry::RETRY_BUDGET;
+use rusoto_core::RusotoError;
+use rusoto_lambda::{InvokeAsyncError, InvokeAsyncRequest, Lambda, LambdaClient};
 use serde_json::Value;
 use std::sync::Arc;
 
@@ -184,13 +192,25 @@ impl LambdaExecutor {
 
     /// Returns the next cloud function names for invocation.
     pub fn next_function(ctx: &ExecutionContext) -> Result<String> {
-        let mut lambdas = match &ctx.next {
-            CloudFunction::None => vec![],
-            CloudFunction::Chorus((name, num)) => {
-                (0..*num).map(|i| format!("{}-{}", name, i)).collect()
+        Self::next_function_scaled(ctx, None)
+    }
+
+    /// Returns the next cloud function names for invocation. If `num_rows` is
+    /// given, the next function is picked among the active members of the
+    /// function group only. See [`LambdaExecutor::active_members`].
+    pub fn next_function_scaled(ctx: &ExecutionContext, num_rows: Option<usize>) -> Result<String> {
+        let mut lambdas = ctx.next.member_names();
+        if let CloudFunction::Chorus((_, num)) = &ctx.next {
+            if let Some(num_rows) = num_rows {
+                lambdas.truncate(Self::active_members(
+                    *num as usize,
+                    num_rows,
+                    globals["lambda"]["rows_per_member"]
+                        .parse::<usize>()
+                        .unwrap(),
+                ));
             }
-            CloudFunction::Solo(name) => vec![name.to_owned()],
-        };
+        }
 
         if lambdas.is_empty() {
             return Err(SquirtleError::Internal(
@@ -207,6 +227,211 @@ impl LambdaExecutor {
 
         Ok(function_name)
     }
+
+    /// Returns how many members of a function group of `group_size` are active
+    /// for `num_rows` input rows: one member per `rows_per_member` rows, at
+    /// least one and at most the whole group. If `rows_per_member` is 0, the
+    /// whole group is active.
+    ///
+    /// Light windows are routed to a few members, which stay warm, instead of
+    /// being spread over the whole group. The active members are the first
+    /// ones of the group, so the set of members a window can be routed to
+    /// depends on its size. A routing scheme that maps keys to members, such as
+    /// hashing, must be stable under changes of the active set, or the same key
+    /// lands on different members across windows.
+    pub fn active_members(group_size: usize, num_rows: usize, rows_per_member: usize) -> usize {
+        if rows_per_member == 0 {
+            return group_size;
+        }
+        ((num_rows + rows_per_member - 1) / rows_per_member)
+            .max(1)
+            .min(group_size)
+    }
+
+    /// Hash-partitions the result batches by the `keys` columns into one
+    /// bucket per member of a function group of `group_size`, so that all the
+    /// rows with the same keys land in the same bucket.
+    ///
+    /// The bucket of a key only depends on the group size, so the buckets are
+    /// sent to the whole group rather than to the active members only. See
+    /// [`LambdaExecutor::active_members`].
+    ///
+    /// The keys are hashed with `hash`. See [`ShuffleHash`].
+    pub async fn shuffle(
+        batches: Vec<RecordBatch>,
+        keys: &[String],
+        group_size: usize,
+        hash: ShuffleHash,
+    ) -> Result<Vec<Vec<RecordBatch>>> {
+        if batches.is_empty() {
+            return Ok(vec![vec![]; group_size]);
+        }
+        if hash != ShuffleHash::DataFusion {
+            return hash.shuffle(&batches, keys, group_size);
+        }
+
+        let schema = batches[0].schema();
+        let exprs = keys
+            .iter()
+            .map(|key| Ok(col(key, &schema)?))
+            .collect::<Result<Vec<_>>>()?;
+        Self::repartition(vec![batches], Partitioning::Hash(exprs, group_size)).await
+    }
+
+    /// Invoke functions in the next stage of the data flow. Returns the sizes
+    /// of the payloads sent.
+    pub fn invoke_next_functions(
+        ctx: &ExecutionContext,
+        batches: &mut Vec<RecordBatch>,
+    ) -> Result<PayloadStats> {
+        // retrieve the next lambda function names
+        let num_rows = batches.iter().map(|b| b.num_rows()).sum();
+        let next_func = LambdaExecutor::next_function_scaled(&ctx, Some(num_rows))?;
+        LambdaExecutor::invoke_function(ctx, &next_func, batches)?.ok_or_else(|| {
+            SquirtleError::Execution(format!("The function {} doesn't exist", next_func))
+        })
+    }
+
+    /// Invoke each member of the next function group with its bucket of the
+    /// shuffled result batches. See [`LambdaExecutor::shuffle`]. Returns the
+    /// sizes of the payloads sent.
+    pub fn invoke_group_members(
+        ctx: &ExecutionContext,
+        buckets: &mut Vec<Vec<RecordBatch>>,
+    ) -> Result<PayloadStats> {
+        let name = match &ctx.next {
+            CloudFunction::Chorus((name, _)) => name,
+            _ => {
+                return Err(SquirtleError::Internal(
+                    "The next function is not a function group".to_owned(),
+                ))
+            }
+        };
+
+        // the buckets of the members that don't exist are handled according to
+        // the unroutable policy, so the first member is invoked last.
+        let mut stats = PayloadStats::default();
+        let mut unroutable = vec![];
+        for (i, bucket) in buckets.iter_mut().enumerate().skip(1) {
+            if !bucket.is_empty() {
+                match LambdaExecutor::invoke_function(ctx, &group_member(name, i), bucket)? {
+                    Some(sent) => stats = stats + sent,
+                    None => {
+                        warn!("The group member {} doesn't exist.", group_member(name, i));
+                        unroutable.append(bucket);
+                    }
+                }
+            }
+        }
+        let dead_letters =
+            ctx.unroutable()?
+                .reroute(unroutable, buckets, "the group members don't exist")?;
+        ctx.write_dead_letters(&dead_letters)?;
+
+        if !buckets[0].is_empty() {
+            let first = group_member(name, 0);
+            stats = stats
+                + LambdaExecutor::invoke_function(ctx, &first, &mut buckets[0])?.ok_or_else(
+                    || SquirtleError::Execution(format!("The function {} doesn't exist", first)),
+                )?;
+        }
+        Ok(stats)
+    }
+
+    /// Invoke the function `next_func` with the record batches, one payload
+    /// per batch. Returns the sizes of the payloads sent, or `None` if
+    /// `next_func` doesn't exist. Returns an error if an invocation is
+    /// rejected in a way that no retry can fix.
+    fn invoke_function(
+        ctx: &ExecutionContext,
+        next_func: &str,
+        batches: &mut Vec<RecordBatch>,
+    ) -> Result<Option<PayloadStats>> {
+        // create uuid builder to assign id to each payload
+        let uuid_builder = UuidBuilder::new(&ctx.name, batches.len());
+
+        let client = &LambdaClient::new(crate::config::aws_region());
+        let stats = batches
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, batch)| {
+                let (invoke_args, stats) = Payload::to_bytes_with_stats(
+                    &batch,
+                    uuid_builder.get(i),
+                    Encoding::default(),
+                    ctx.watermark,
+                );
+
+                // call the lambda function asynchronously until it succeeds.
+                let mut backoffs = 0;
+                loop {
+                    let request = InvokeAsyncRequest {
+                        function_name: next_func.to_owned(),
+                        invoke_args:   invoke_args.clone(),
+                    };
+
+                    match block_on(client.invoke_async(request)) {
+                        Ok(reponse) => {
+                            if let Some(code) = reponse.status {
+                                // A success response (202 Accepted) indicates that the
+                                // request is queued for invocation.
+                                if code == 202 {
+                                    RETRY_BUDGET.deposit();
+                                    break;
+                                } else {
+                                    warn!("Unknown invoke error: {}, retry ... ", code);
+                                }
+                            }
+                        }
+                        // retrying a function that doesn't exist never succeeds.
+                        Err(RusotoError::Service(InvokeAsyncError::ResourceNotFound(_))) => {
+                            return Ok(None);
+                        }
+                        // neither does retrying a request that the service rejects.
+                        Err(e) if is_permanent(&e) => {
+                            return Err(SquirtleError::Execution(format!(
+                                "Failed to invoke {}: {}",
+                                next_func, e
+                            )));
+                        }
+                        Err(e) => warn!("Failed to invoke {}: {}, retry ... ", next_func, e),
+                    }
+
+                    // the retries of all invocations share one budget, so that they
+                    // don't turn throttling into a retry storm. Even the retries paid
+                    // for by the budget are spread out by a jittered delay.
+                    if RETRY_BUDGET.try_retry() {
+                        std::thread::sleep(RETRY_BUDGET.jitter());
+                    } else {
+                        std::thread::sleep(RETRY_BUDGET.backoff(backoffs));
+                        backoffs += 1;
+                    }
+                }
+                Ok(Some(stats))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .collect::<Option<Vec<_>>>();
+
+        Ok(stats.map(|stats| {
+            stats
+                .into_iter()
+                .fold(PayloadStats::default(), |a, b| a + b)
+        }))
+    }
+}
+
+/// Returns true if an invocation failed in a way that no retry can fix, e.g.
+/// because the payload is malformed or too large.
+fn is_permanent(e: &RusotoError<InvokeAsyncError>) -> bool {
+    match e {
+        RusotoError::Service(InvokeAsyncError::InvalidRequestContent(_))
+        | RusotoError::Service(InvokeAsyncError::InvalidRuntime(_))
+        | RusotoError::Validation(_) => true,
+        // 413 Request Entity Too Large
+        RusotoError::Unknown(response) => response.status.as_u16() == 413,
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -218,7 +443,7 @@ mod tests {
     use arrow::datatypes::{DataType, Field, Schema};
     use aws_lambda_events::event::kinesis::KinesisEvent;
     use datafusion::datasource::MemTable;
-    use datafusion::physical_plan::expressions::{col, Column};
+    use datafusion::physical_plan::expressions::Column;
     use tokio::task::JoinHandle;
 
     #[tokio::test]
@@ -368,6 +593,62 @@ mod tests {
         .unwrap()
     }
 
+    #[tokio::test]
+    async fn shuffle_by_key() -> Result<()> {
+        let schema = test_schema();
+        let batches = create_vec_batches(&schema, 10);
+
+        let buckets =
+            LambdaExecutor::shuffle(batches, &["c0".to_owned()], 4, ShuffleHash::default()).await?;
+        assert_eq!(4, buckets.len());
+
+        // Every key lands in one and only one bucket.
+        let mut owners = std::collections::HashMap::new();
+        let mut total_rows = 0;
+        for (i, bucket) in buckets.iter().enumerate() {
+            for batch in bucket {
+                let keys = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<UInt32Array>()
+                    .unwrap();
+                for key in keys.values() {
+                    assert_eq!(i, *owners.entry(*key).or_insert(i));
+                }
+                total_rows += batch.num_rows();
+            }
+        }
+        assert_eq!(8, owners.len());
+        assert_eq!(80, total_rows);
+
+        // Unknown key columns are rejected.
+        assert!(LambdaExecutor::shuffle(
+            create_vec_batches(&schema, 1),
+            &["c1".to_owned()],
+            4,
+            ShuffleHash::default()
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn active_members() {
+        // The whole group is active if the mode is disabled.
+        assert_eq!(24, LambdaExecutor::active_members(24, 0, 0));
+        assert_eq!(24, LambdaExecutor::active_members(24, 1_000_000, 0));
+
+        // At least one member is active.
+        assert_eq!(1, LambdaExecutor::active_members(24, 0, 1000));
+        assert_eq!(1, LambdaExecutor::active_members(24, 1000, 1000));
+        assert_eq!(2, LambdaExecutor::active_members(24, 1001, 1000));
+
+        // At most the whole group is active.
+        assert_eq!(24, LambdaExecutor::active_members(24, 1_000_000, 1000));
+    }
+
     #[tokio::test]
     async fn next_function() -> Result<()> {
         let input = include_str!("../../../test/data/example-kinesis-event-1.json");
@@ -428,6 +709,26 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn permanent_invoke_errors() {
+        let service = RusotoError::Service;
+        assert!(is_permanent(&service(
+            InvokeAsyncError::InvalidRequestContent("malformed".to_owned())
+        )));
+        assert!(is_permanent(&RusotoError::Validation(
+            "too large".to_owned()
+        )));
+        assert!(!is_permanent(&service(InvokeAsyncError::ResourceConflict(
+            "pending update".to_owned()
+        ))));
+        assert!(!is_permanent(&service(InvokeAsyncError::Service(
+            "throttled".to_owned()
+        ))));
+    }
 }
 
+pub mod hash;
 pub mod plan;
+pub mod reshard;
+pub mod retry;
@@ -0,0 +1,262 @@
+// Copyright (c) 2021 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Re-sharding a function group, i.e., changing the number of its members.
+//!
+//! The member that owns a shuffle key depends on the group size, so resizing a
+//! group moves some keys to other members. For a stateless stage, it's enough
+//! to route the results to the group of the new size, see
+//! [`Reshard::rerouted`]. A stateful stage must also move the state of the
+//! moved keys to their new owners, see [`Reshard::migrate`] and
+//! [`MemberState`].
+
+use super::hash::ShuffleHash;
+use super::LambdaExecutor;
+use crate::context::{CloudFunction, ExecutionContext};
+use crate::error::{Result, SquirtleError};
+use arrow::record_batch::RecordBatch;
+use std::convert::TryFrom;
+
+/// The keyed state kept by the members of a function group, such as the
+/// partial aggregates of a stateful aggregation.
+pub trait MemberState {
+    /// Removes and returns the state rows of `member`.
+    fn drain(&mut self, member: usize) -> Result<Vec<RecordBatch>>;
+    /// Adds the state rows to `member`.
+    fn replay(&mut self, member: usize, batches: Vec<RecordBatch>) -> Result<()>;
+}
+
+/// The rows of the keys that move from one member to another.
+#[derive(Debug, Clone)]
+pub struct Move {
+    /// The member that owns the keys in the group of the old size.
+    pub from:    usize,
+    /// The member that owns the keys in the group of the new size.
+    pub to:      usize,
+    /// The rows of the keys.
+    pub batches: Vec<RecordBatch>,
+}
+
+/// A change of the size of a function group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reshard {
+    /// The group size before the change.
+    pub old_size: usize,
+    /// The group size after the change.
+    pub new_size: usize,
+    /// The hash function of the shuffle keys.
+    pub hash:     ShuffleHash,
+}
+
+impl Reshard {
+    /// Returns the change of a group from `old_size` to `new_size` members.
+    /// Both sizes must be between 1 and 255.
+    pub fn new(old_size: usize, new_size: usize, hash: ShuffleHash) -> Result<Self> {
+        for size in &[old_size, new_size] {
+            if *size == 0 || u8::try_from(*size).is_err() {
+                return Err(SquirtleError::Internal(format!(
+                    "Invalid function group size: {}",
+                    size
+                )));
+            }
+        }
+        Ok(Self {
+            old_size,
+            new_size,
+            hash,
+        })
+    }
+
+    /// Returns the context of a stateless upstream function, routing its
+    /// results to the group of the new size. The new members must be deployed
+    /// before the context is.
+    pub fn rerouted(&self, ctx: &ExecutionContext) -> Result<ExecutionContext> {
+        match &ctx.next {
+            CloudFunction::Chorus((name, size)) if *size as usize == self.old_size => {
+                Ok(ExecutionContext {
+                    next: CloudFunction::Chorus((name.clone(), self.new_size as u8)),
+                    ..ctx.clone()
+                })
+            }
+            next => Err(SquirtleError::Internal(format!(
+                "{} doesn't route to a group of {} members: {}",
+                ctx.name, self.old_size, next
+            ))),
+        }
+    }
+
+    /// Returns the rows of the keys that change owners, grouped by their old
+    /// and new owners. The rows of the other keys are left out.
+    pub async fn moves(&self, batches: Vec<RecordBatch>, keys: &[String]) -> Result<Vec<Move>> {
+        let old = LambdaExecutor::shuffle(batches, keys, self.old_size, self.hash).await?;
+        let mut moves = vec![];
+        for (from, bucket) in old.into_iter().enumerate() {
+            if bucket.is_empty() {
+                continue;
+            }
+            let new = LambdaExecutor::shuffle(bucket, keys, self.new_size, self.hash).await?;
+            for (to, batches) in new.into_iter().enumerate() {
+                if to != from && batches.iter().any(|b| b.num_rows() > 0) {
+                    moves.push(Move { from, to, batches });
+                }
+            }
+        }
+        Ok(moves)
+    }
+
+    /// Moves the state of the keys that change owners to their new owners.
+    /// The state of each member of the old group is drained, and the rows of
+    /// the keys it still owns are replayed back to it. Returns the number of
+    /// rows moved.
+    pub async fn migrate(&self, state: &mut dyn MemberState, keys: &[String]) -> Result<usize> {
+        let mut moved = 0;
+        for member in 0..self.old_size {
+            let rows = state.drain(member)?;
+            let buckets = LambdaExecutor::shuffle(rows, keys, self.new_size, self.hash).await?;
+            for (owner, batches) in buckets.into_iter().enumerate() {
+                let num_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+                if num_rows == 0 {
+                    continue;
+                }
+                if owner != member {
+                    moved += num_rows;
+                }
+                state.replay(owner, batches)?;
+            }
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::UInt32Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn batch() -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("k", DataType::UInt32, false)]));
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![Arc::new(UInt32Array::from((0..256).collect::<Vec<u32>>()))],
+        )?)
+    }
+
+    fn keys(batches: &[RecordBatch]) -> Vec<u32> {
+        let mut keys = batches
+            .iter()
+            .flat_map(|b| {
+                let column = b.column(0).as_any().downcast_ref::<UInt32Array>().unwrap();
+                column.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[derive(Default)]
+    struct InMemoryState(HashMap<usize, Vec<RecordBatch>>);
+
+    impl MemberState for InMemoryState {
+        fn drain(&mut self, member: usize) -> Result<Vec<RecordBatch>> {
+            Ok(self.0.remove(&member).unwrap_or_default())
+        }
+
+        fn replay(&mut self, member: usize, batches: Vec<RecordBatch>) -> Result<()> {
+            self.0.entry(member).or_default().extend(batches);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reroute_stateless() -> Result<()> {
+        assert!(Reshard::new(0, 8, ShuffleHash::Murmur2).is_err());
+        assert!(Reshard::new(4, 256, ShuffleHash::Murmur2).is_err());
+
+        let reshard = Reshard::new(4, 8, ShuffleHash::Murmur2)?;
+        let ctx = ExecutionContext {
+            name: "q5-00".to_owned(),
+            next: CloudFunction::Chorus(("q5-01".to_owned(), 4)),
+            ..Default::default()
+        };
+        let rerouted = reshard.rerouted(&ctx)?;
+        assert_eq!(
+            CloudFunction::Chorus(("q5-01".to_owned(), 8)),
+            rerouted.next
+        );
+        assert_eq!(ctx.plan_summary(), rerouted.plan_summary());
+
+        // A context of another group size is not rerouted twice.
+        assert!(reshard.rerouted(&rerouted).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn moved_keys() -> Result<()> {
+        let columns = ["k".to_owned()];
+        for hash in &[ShuffleHash::Murmur2, ShuffleHash::Blake2b] {
+            let reshard = Reshard::new(4, 8, *hash)?;
+            let moves = reshard.moves(vec![batch()?], &columns).await?;
+
+            // Each moved key goes to the member that owns it in the new group.
+            let mut moved = vec![];
+            for m in &moves {
+                assert_ne!(m.from, m.to);
+                for key in keys(&m.batches) {
+                    let key = key.to_string();
+                    assert_eq!(Some(m.from), hash.bucket(key.as_bytes(), 4));
+                    assert_eq!(Some(m.to), hash.bucket(key.as_bytes(), 8));
+                }
+                moved.extend(keys(&m.batches));
+            }
+            assert!(!moved.is_empty() && moved.len() < 256);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_state() -> Result<()> {
+        let columns = ["k".to_owned()];
+        let hash = ShuffleHash::Murmur2;
+        let old = LambdaExecutor::shuffle(vec![batch()?], &columns, 4, hash).await?;
+        let mut state = InMemoryState::default();
+        for (member, batches) in old.into_iter().enumerate() {
+            state.replay(member, batches)?;
+        }
+
+        let reshard = Reshard::new(4, 8, hash)?;
+        let expected: usize = reshard
+            .moves(vec![batch()?], &columns)
+            .await?
+            .iter()
+            .map(|m| m.batches.iter().map(|b| b.num_rows()).sum::<usize>())
+            .sum();
+        assert_eq!(expected, reshard.migrate(&mut state, &columns).await?);
+
+        // No key is lost, and each key is kept by its owner in the new group.
+        let mut all = vec![];
+        for (member, batches) in &state.0 {
+            for key in keys(batches) {
+                assert_eq!(Some(*member), hash.bucket(key.to_string().as_bytes(), 8));
+                all.push(key);
+            }
+        }
+        all.sort_unstable();
+        assert_eq!((0..256).collect::<Vec<u32>>(), all);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (c) 2020 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+// Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! A retry budget shared by all invocations of a cloud function instance.
+//!
+//! If every invocation retried on its own, a large fan-out under throttling
+//! would turn into a retry storm that only makes the throttling worse. Instead,
+//! each retry withdraws a token from a shared bucket, and each successful
+//! invocation deposits a fraction of a token back. A retry paid for by the
+//! bucket waits a jittered fraction of the initial backoff. Once the bucket is
+//! empty, the invocations back off exponentially before retrying, so the
+//! retries are rate-limited globally rather than per call.
+
+use crate::config::GLOBALS as globals;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    /// The retry budget of the function instance, configured by the `retry_*`
+    /// settings in the `[lambda]` section of `squirtle.toml`.
+    pub static ref RETRY_BUDGET: RetryBudget = RetryBudget::new(
+        globals["lambda"]["retry_budget"].parse::<usize>().unwrap(),
+        globals["lambda"]["retry_refill"].parse::<f64>().unwrap(),
+        Duration::from_millis(globals["lambda"]["retry_backoff"].parse::<u64>().unwrap()),
+    );
+}
+
+/// The longest backoff before a retry once the budget is exhausted.
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The state of a retry budget.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetryStats {
+    /// The number of tokens left in the budget.
+    pub tokens:    f64,
+    /// The number of retries paid for by the budget.
+    pub retries:   usize,
+    /// The number of retries that found the budget exhausted and backed off.
+    pub exhausted: usize,
+}
+
+impl RetryStats {
+    /// Returns the retries and backoffs since an `earlier` state of the same
+    /// budget, and the tokens left now.
+    pub fn since(&self, earlier: &RetryStats) -> RetryStats {
+        RetryStats {
+            tokens:    self.tokens,
+            retries:   self.retries.saturating_sub(earlier.retries),
+            exhausted: self.exhausted.saturating_sub(earlier.exhausted),
+        }
+    }
+}
+
+impl fmt::Display for RetryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} retries, {} backoffs, {:.1} tokens left",
+            self.retries, self.exhausted, self.tokens
+        )
+    }
+}
+
+/// A token bucket that limits the retries of all invocations.
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// The maximum number of tokens in the budget.
+    capacity: f64,
+    /// The tokens a successful invocation deposits back.
+    refill:   f64,
+    /// The backoff before the first retry once the budget is exhausted.
+    backoff:  Duration,
+    /// The tokens left and the retries so far.
+    stats:    Mutex<RetryStats>,
+}
+
+impl RetryBudget {
+    /// Returns a full budget of `capacity` retries. Each successful invocation
+    /// deposits `refill` tokens back.
+    pub fn new(capacity: usize, refill: f64, backoff: Duration) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill,
+            backoff,
+            stats: Mutex::new(RetryStats {
+                tokens: capacity as f64,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Withdraws a token for a retry. Returns false if the budget is exhausted,
+    /// in which case the caller must back off before retrying.
+    pub fn try_retry(&self) -> bool {
+        let mut stats = self.stats.lock().unwrap();
+        if stats.tokens >= 1.0 {
+            stats.tokens -= 1.0;
+            stats.retries += 1;
+            true
+        } else {
+            stats.exhausted += 1;
+            false
+        }
+    }
+
+    /// Deposits the refill of a successful invocation back into the budget.
+    pub fn deposit(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.tokens = (stats.tokens + self.refill).min(self.capacity);
+    }
+
+    /// Returns the delay before a retry paid for by the budget: a random
+    /// fraction of the initial backoff, so that the retries of a throttled
+    /// fan-out aren't sent back to back.
+    pub fn jitter(&self) -> Duration {
+        self.backoff.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Returns the backoff before the `attempt`-th retry (from 0) of an
+    /// invocation that found the budget exhausted.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(1u32 << attempt.min(16))
+            .map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF))
+    }
+
+    /// Returns the current state of the budget.
+    pub fn stats(&self) -> RetryStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_budget() {
+        let budget = RetryBudget::new(2, 0.5, Duration::from_millis(100));
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        // Two successful invocations pay for one more retry.
+        budget.deposit();
+        assert!(!budget.try_retry());
+        budget.deposit();
+        assert!(budget.try_retry());
+
+        // The budget never exceeds its capacity.
+        (0..10).for_each(|_| budget.deposit());
+        assert_eq!(
+            RetryStats {
+                tokens:    2.0,
+                retries:   3,
+                exhausted: 2,
+            },
+            budget.stats()
+        );
+
+        // The retries of an invocation are the difference of two states.
+        let before = budget.stats();
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+        assert_eq!(
+            RetryStats {
+                tokens:    0.0,
+                retries:   2,
+                exhausted: 1,
+            },
+            budget.stats().since(&before)
+        );
+
+        assert!(budget.jitter() <= Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), budget.backoff(0));
+        assert_eq!(Duration::from_millis(400), budget.backoff(2));
+        assert_eq!(MAX_RETRY_BACKOFF, budget.backoff(100));
+    }
+}
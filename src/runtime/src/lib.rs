@@ -34,12 +34,16 @@
 extern crate abomonation_derive;
 
 pub mod arena;
+pub mod clock;
 pub mod config;
 pub mod context;
+pub mod datasink;
 pub mod datasource;
 pub mod encoding;
 pub mod error;
 pub mod executor;
+pub mod logger;
+pub mod naming;
 pub mod payload;
 pub mod prelude;
 pub mod query;
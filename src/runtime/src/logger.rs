@@ -0,0 +1,190 @@
+// Copyright (c) 2021 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The logger of the cloud functions.
+//!
+//! AWS Lambda turns every line written to stdout into a CloudWatch log event.
+//! In production, the logger buffers the log lines and writes them out
+//! together, separated by carriage returns, so that they are ingested as a
+//! single log event. Elsewhere, each line is written out right away.
+//!
+//! Lines below the level named by the environment variable configured as
+//! `log_level` in `squirtle.toml` are dropped. The level is `info` unless the
+//! variable is set, e.g. to `debug` for a local run.
+
+use crate::config::GLOBALS as globals;
+use crate::error::{Result, SquirtleError};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// A logger that buffers up to `capacity` log lines before writing them out
+/// as one log event.
+pub struct BufferedLogger {
+    level:    LevelFilter,
+    capacity: usize,
+    lines:    Mutex<Vec<String>>,
+    out:      Mutex<Box<dyn Write + Send>>,
+}
+
+impl BufferedLogger {
+    /// Returns a logger of the records at or above `level` that writes to
+    /// `out` every `capacity` lines. A capacity of 1 writes each line through.
+    pub fn new(level: LevelFilter, capacity: usize, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            level,
+            capacity: capacity.max(1),
+            lines: Mutex::new(vec![]),
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let full = {
+            let mut lines = self.lines.lock().unwrap();
+            lines.push(format!(
+                "[{} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            ));
+            lines.len() >= self.capacity
+        };
+        if full {
+            self.flush();
+        }
+    }
+
+    fn flush(&self) {
+        let lines = std::mem::take(&mut *self.lines.lock().unwrap());
+        if lines.is_empty() {
+            return;
+        }
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", lines.join("\r"));
+        let _ = out.flush();
+    }
+}
+
+/// Returns the log level named by `name`, such as `warn`, or `info` if `name`
+/// is absent or empty.
+pub fn log_level(name: Option<&str>) -> Result<LevelFilter> {
+    match name.map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        Some(name) => name
+            .parse()
+            .map_err(|_| SquirtleError::Internal(format!("Unknown log level: {}", name))),
+        None => Ok(LevelFilter::Info),
+    }
+}
+
+/// Installs the logger of the cloud function. It must be called once, before
+/// anything is logged.
+pub fn init() -> Result<()> {
+    let production = globals["project"]["production"].parse::<bool>().unwrap();
+    let level = log_level(
+        std::env::var(&globals["lambda"]["log_level"])
+            .ok()
+            .as_deref(),
+    )?;
+    let capacity = if production {
+        globals["lambda"]["log_buffer"].parse::<usize>().unwrap()
+    } else {
+        1
+    };
+
+    let logger = BufferedLogger::new(level, capacity, Box::new(std::io::stdout()));
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|e| SquirtleError::Internal(e.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Writes out the buffered log lines. The cloud function calls it at the end
+/// of each invocation, since the instance may be frozen afterwards.
+pub fn flush() {
+    log::logger().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log(logger: &BufferedLogger, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .target("test")
+                .build(),
+        );
+    }
+
+    #[test]
+    fn buffered_lines() {
+        let out = SharedBuffer::default();
+        let logger = BufferedLogger::new(LevelFilter::Info, 3, Box::new(out.clone()));
+        let written = || String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+
+        log(&logger, Level::Info, "one");
+        log(&logger, Level::Debug, "dropped");
+        log(&logger, Level::Warn, "two");
+        assert_eq!("", written());
+
+        // The third line fills the buffer, and the lines go out as one event.
+        log(&logger, Level::Info, "three");
+        assert_eq!(
+            "[INFO test] one\r[WARN test] two\r[INFO test] three\n",
+            written()
+        );
+
+        log(&logger, Level::Error, "four");
+        logger.flush();
+        logger.flush();
+        assert!(written().ends_with("three\n[ERROR test] four\n"));
+    }
+
+    #[test]
+    fn levels() -> Result<()> {
+        assert_eq!(LevelFilter::Info, log_level(None)?);
+        assert_eq!(LevelFilter::Info, log_level(Some(" "))?);
+        assert_eq!(LevelFilter::Debug, log_level(Some("debug"))?);
+        assert_eq!(LevelFilter::Warn, log_level(Some("WARN"))?);
+        assert_eq!(LevelFilter::Off, log_level(Some("off"))?);
+        assert!(log_level(Some("loud")).is_err());
+        Ok(())
+    }
+}
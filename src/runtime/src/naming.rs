@@ -0,0 +1,297 @@
+// Copyright (c) 2020 UMD Database Group. All Rights Reserved.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+// Only bring in dependencies for the repl when the cli feature is enabled.
+
+//! The naming convention of the cloud functions in a query.
+//!
+//! A function name is made of the query code, the index of the subplan in the
+//! dataflow graph, the time the query was deployed and, for the members of a
+//! function group, the index of the member in the group:
+//!
+//! `{query code}-{plan index}-{timestamp}-{group index}`
+//!
+//! For example, `SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836Z-3`.
+
+use crate::error::{Result, SquirtleError};
+use blake2::{Blake2b, Digest};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// The typed form of a cloud function name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct FunctionName {
+    /// The hash code of the query, or a short code such as `q5` for the
+    /// benchmark queries. It must not contain `-`.
+    pub query_code: String,
+    /// The index of the subplan in the dataflow graph.
+    pub plan_idx:   usize,
+    /// The time the query was deployed, in the `Debug` format of
+    /// `chrono::DateTime<Utc>`. It must not end with `-{digits}`.
+    pub timestamp:  Option<String>,
+    /// The index of the function in its function group.
+    pub group_idx:  Option<usize>,
+}
+
+impl FunctionName {
+    /// Returns a new function name for the subplan `plan_idx` of a query.
+    pub fn new(query_code: &str, plan_idx: usize) -> Self {
+        Self {
+            query_code: query_code.to_owned(),
+            plan_idx,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the function name with the given deployment time.
+    pub fn with_timestamp(mut self, timestamp: &str) -> Self {
+        self.timestamp = Some(timestamp.to_owned());
+        self
+    }
+
+    /// Returns the function name of the `group_idx`-th member of the group.
+    pub fn with_group(mut self, group_idx: usize) -> Self {
+        self.group_idx = Some(group_idx);
+        self
+    }
+
+    /// Returns the function name without the group index, i.e., the name of
+    /// the function group.
+    pub fn group(&self) -> Self {
+        Self {
+            group_idx: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the identifier of the query triggered at the specific time.
+    pub fn tid(&self) -> String {
+        match &self.timestamp {
+            Some(timestamp) => format!("{}-{}", self.query_code, timestamp),
+            None => self.query_code.to_owned(),
+        }
+    }
+}
+
+/// The length of the query code derived from the query SQL.
+pub const QUERY_CODE_LEN: usize = 16;
+
+/// Returns the query code of `sql`, i.e., the truncated BLAKE2b digest of the
+/// query in hex. Identical queries have identical codes, so their functions
+/// can be reused across runs.
+///
+/// The code is alphanumeric: base64 would add `+` and `/`, which are not
+/// allowed in function names, or `-` in its URL-safe form, which separates the
+/// parts of a function name.
+pub fn query_code(sql: &str) -> String {
+    let mut code = format!("{:x}", Blake2b::digest(sql.as_bytes()));
+    code.truncate(QUERY_CODE_LEN);
+    code
+}
+
+/// Returns the name of the `group_idx`-th member of the function group `name`.
+///
+/// Unlike [`FunctionName::with_group`], the group name doesn't have to follow
+/// the naming convention.
+pub fn group_member(name: &str, group_idx: usize) -> String {
+    format!("{}-{}", name, group_idx)
+}
+
+/// Returns the names of the first `group_size` members of the function group
+/// `name`, in strict order of the group index.
+pub fn group_members(name: &str, group_size: usize) -> Vec<String> {
+    (0..group_size).map(|i| group_member(name, i)).collect()
+}
+
+/// Function names are ordered by query code, plan index and group index, so
+/// that the members of a group are listed in index order after the group
+/// itself. The deployment time only breaks ties.
+impl Ord for FunctionName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (
+            &self.query_code,
+            self.plan_idx,
+            self.group_idx,
+            &self.timestamp,
+        )
+            .cmp(&(
+                &other.query_code,
+                other.plan_idx,
+                other.group_idx,
+                &other.timestamp,
+            ))
+    }
+}
+
+impl PartialOrd for FunctionName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for FunctionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{:02}", self.query_code, self.plan_idx)?;
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "-{}", timestamp)?;
+        }
+        if let Some(group_idx) = self.group_idx {
+            write!(f, "-{}", group_idx)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FunctionName {
+    type Err = SquirtleError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        let malformed = || SquirtleError::Internal(format!("Malformed function name: {}", name));
+        let is_index = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+        let mut parts = name.splitn(3, '-');
+        let query_code = parts
+            .next()
+            .filter(|s| !s.is_empty())
+            .ok_or_else(malformed)?;
+        let plan_idx = parts.next().filter(|s| is_index(s)).ok_or_else(malformed)?;
+        let (timestamp, group_idx) = match parts.next() {
+            None => (None, None),
+            Some("") => return Err(malformed()),
+            Some(rest) if is_index(rest) => (None, Some(rest)),
+            Some(rest) => match rest.rsplitn(2, '-').collect::<Vec<_>>().as_slice() {
+                [group_idx, timestamp] if is_index(group_idx) => {
+                    (Some(*timestamp), Some(*group_idx))
+                }
+                _ => (Some(rest), None),
+            },
+        };
+
+        Ok(Self {
+            query_code: query_code.to_owned(),
+            plan_idx:   plan_idx.parse().map_err(|_| malformed())?,
+            timestamp:  timestamp.map(|s| s.to_owned()),
+            group_idx:  match group_idx {
+                Some(idx) => Some(idx.parse().map_err(|_| malformed())?),
+                None => None,
+            },
+        })
+    }
+}
+
+impl TryFrom<&str> for FunctionName {
+    type Error = SquirtleError;
+
+    fn try_from(name: &str) -> Result<Self> {
+        name.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_name_roundtrip() -> Result<()> {
+        let timestamp = "2021-01-28T19:27:50.298504836Z";
+
+        let name = FunctionName::new("SX72HzqFz1Qij4bP", 0).with_timestamp(timestamp);
+        assert_eq!(
+            "SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836Z",
+            name.to_string()
+        );
+        assert_eq!(name, name.to_string().parse()?);
+        assert_eq!(
+            "SX72HzqFz1Qij4bP-2021-01-28T19:27:50.298504836Z",
+            name.tid()
+        );
+
+        let member = name.clone().with_group(12);
+        assert_eq!(
+            "SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836Z-12",
+            member.to_string()
+        );
+        assert_eq!(member, FunctionName::try_from(member.to_string().as_str())?);
+        assert_eq!(name, member.group());
+        assert_eq!(member.to_string(), group_member(&name.to_string(), 12));
+
+        let name = FunctionName::new("q5", 1);
+        assert_eq!("q5-01", name.to_string());
+        assert_eq!(name, "q5-01".parse()?);
+        assert_eq!(name.clone().with_group(3), "q5-01-3".parse()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn blake2b_query_code() -> Result<()> {
+        let sql = "SELECT b FROM t ORDER BY b ASC LIMIT 3";
+        assert_eq!(QUERY_CODE_LEN, query_code(sql).len());
+        assert_eq!(query_code(sql), query_code(sql));
+        assert_ne!(query_code(sql), query_code("SELECT a FROM t"));
+
+        // Every code is a valid part of a function name, i.e., it matches
+        // `^[A-Za-z0-9]{16}$`.
+        for i in 0..1000 {
+            let code = query_code(&format!("SELECT {} FROM t", i));
+            assert_eq!(QUERY_CODE_LEN, code.len());
+            assert!(code.bytes().all(|b| b.is_ascii_alphanumeric()), "{}", code);
+            let name = FunctionName::new(&code, 0);
+            assert_eq!(name, name.to_string().parse()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn function_name_order() -> Result<()> {
+        let group = FunctionName::new("q5", 1).with_timestamp("2021-01-28T19:27:50Z");
+        assert_eq!(
+            vec![
+                "q5-01-2021-01-28T19:27:50Z-0",
+                "q5-01-2021-01-28T19:27:50Z-1"
+            ],
+            group_members(&group.to_string(), 2)
+        );
+
+        // Lexicographically, member 10 comes before member 2.
+        let mut names: Vec<FunctionName> = vec!["q5-00-3".parse()?, "q4-02".parse()?];
+        for member in group_members(&group.to_string(), 12) {
+            names.push(member.parse()?);
+        }
+        names.push(group.clone());
+        names.reverse();
+        names.sort();
+
+        assert_eq!(FunctionName::new("q4", 2), names[0]);
+        assert_eq!(FunctionName::new("q5", 0).with_group(3), names[1]);
+        assert_eq!(group, names[2]);
+        for (i, name) in names[3..].iter().enumerate() {
+            assert_eq!(group.clone().with_group(i), *name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_function_name() {
+        for name in &["", "q5", "-00", "q5-", "q5-x1", "q5--2021", "q5-00-"] {
+            assert!(
+                name.parse::<FunctionName>().is_err(),
+                "{} should be malformed",
+                name
+            );
+        }
+    }
+}
@@ -17,6 +17,7 @@
 
 use crate::encoding::Encoding;
 use crate::error::{Result, SquirtleError};
+use crate::naming::FunctionName;
 use abomonation::{decode, encode};
 use arrow::datatypes::{Schema, SchemaRef};
 use arrow::record_batch::RecordBatch;
@@ -26,8 +27,9 @@ use arrow_flight::SchemaAsIpc;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+use std::ops::Add;
 use std::sync::Arc;
-use text_io::scan;
 
 /// A helper function to build UUIDs of a series of payloads for a given query.
 #[derive(Default, Debug)]
@@ -44,15 +46,11 @@ pub struct UuidBuilder {
 impl UuidBuilder {
     /// Returns a new UuidBuilder.
     pub fn new(function_name: &str, len: usize) -> Self {
-        let query_code: String;
-        let plan_inedx: String;
-        let timestamp: String;
-        scan!(function_name.bytes() => "{}-{}-{}", query_code, plan_inedx, timestamp);
-        Self {
-            tid: format!("{}-{}", query_code, timestamp),
-            pos: 0,
-            len,
-        }
+        let tid = match function_name.parse::<FunctionName>() {
+            Ok(name) => name.tid(),
+            Err(_) => function_name.to_owned(),
+        };
+        Self { tid, pos: 0, len }
     }
 
     /// Returns the next Uuid for the next payload.
@@ -119,6 +117,40 @@ pub struct DataFrame {
     body:   Vec<u8>,
 }
 
+/// The sizes of the payloads sent to the next functions, to see how much data
+/// each stage of the dataflow ships and how well it compresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PayloadStats {
+    /// The number of payloads.
+    pub payloads:      usize,
+    /// The size of the Arrow Flight data in the payloads before compression.
+    pub raw_bytes:     usize,
+    /// The size of the serialized payloads as sent.
+    pub encoded_bytes: usize,
+}
+
+impl Add for PayloadStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            payloads:      self.payloads + other.payloads,
+            raw_bytes:     self.raw_bytes + other.raw_bytes,
+            encoded_bytes: self.encoded_bytes + other.encoded_bytes,
+        }
+    }
+}
+
+impl fmt::Display for PayloadStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} payloads, {} bytes ({} bytes before compression)",
+            self.payloads, self.encoded_bytes, self.raw_bytes
+        )
+    }
+}
+
 /// `Payload` is the raw structure of the function's payload passed between
 /// lambda functions. In AWS Lambda, it supports payload sizes up to 256KB for
 /// async invocation. You can pass payloads in your query workflows, allowing
@@ -126,15 +158,21 @@ pub struct DataFrame {
 #[derive(Default, Debug, Abomonation, Deserialize, Serialize, PartialEq)]
 pub struct Payload {
     /// The data batches in the payload.
-    pub data:     Vec<DataFrame>,
+    pub data:      Vec<DataFrame>,
     /// The subplan's schema.
     #[serde(with = "serde_bytes")]
-    pub schema:   Vec<u8>,
+    pub schema:    Vec<u8>,
     /// The query's uuid.
-    pub uuid:     Uuid,
+    pub uuid:      Uuid,
     /// Compress `DataFrame` to guarantee the total size
     /// of payload doesn't exceed 256 KB.
-    pub encoding: Encoding,
+    pub encoding:  Encoding,
+    /// The event-time watermark (ms) of the data: no later event is expected
+    /// with an earlier event time. A function that reassembles the payloads of
+    /// several upstream functions advances its watermark to the minimum of
+    /// their watermarks, and to `None` if any of them has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<i64>,
 }
 
 impl Payload {
@@ -151,6 +189,11 @@ impl Payload {
         Ok(Arc::new(schema))
     }
 
+    /// Returns the watermark of an incoming payload without decoding its data.
+    pub fn watermark_of(event: &Value) -> Option<i64> {
+        event.get("watermark").and_then(Value::as_i64)
+    }
+
     /// Convert incoming payload to record batch in Arrow.
     pub fn to_batch(event: Value) -> (Vec<RecordBatch>, Uuid) {
         let payload: Payload = serde_json::from_value(event).unwrap();
@@ -204,6 +247,7 @@ impl Payload {
             schema: Self::schema_to_bytes(batches[0].schema()),
             uuid,
             encoding,
+            watermark: None,
         })
         .unwrap()
     }
@@ -234,15 +278,29 @@ impl Payload {
             schema: Self::schema_to_bytes(batches[0].schema()),
             uuid,
             encoding,
+            watermark: None,
         })
         .unwrap()
     }
 
     /// Convert record batch to bytes for network transmission.
     pub fn to_bytes(batch: &RecordBatch, uuid: Uuid, encoding: Encoding) -> bytes::Bytes {
+        Self::to_bytes_with_stats(batch, uuid, encoding, None).0
+    }
+
+    /// Convert record batch to bytes for network transmission, and measure the
+    /// size of the payload before and after compression. The payload carries
+    /// the given event-time `watermark`.
+    pub fn to_bytes_with_stats(
+        batch: &RecordBatch,
+        uuid: Uuid,
+        encoding: Encoding,
+        watermark: Option<i64>,
+    ) -> (bytes::Bytes, PayloadStats) {
         let options = arrow::ipc::writer::IpcWriteOptions::default();
         let schema = Self::schema_to_bytes(batch.schema());
         let (_, flight_data) = flight_data_from_arrow_batch(batch, &options);
+        let raw_bytes = flight_data.data_header.len() + flight_data.data_body.len();
 
         let data_frames = {
             if encoding != Encoding::None {
@@ -258,30 +316,38 @@ impl Payload {
             }
         };
 
-        serde_json::to_vec(&Payload {
+        let bytes: bytes::Bytes = serde_json::to_vec(&Payload {
             data: vec![data_frames],
             schema,
             uuid,
             encoding,
+            watermark,
         })
         .unwrap()
-        .into()
+        .into();
+        let stats = PayloadStats {
+            payloads: 1,
+            raw_bytes,
+            encoded_bytes: bytes.len(),
+        };
+        (bytes, stats)
     }
 }
 
 /// Deserialize `DataFrame` from cloud functions.
 pub fn unmarshal(payload: Payload) -> Vec<DataFrame> {
     match payload.encoding {
-        Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => payload
-            .data
-            .par_iter()
-            .map(|d| DataFrame {
-                header: payload.encoding.decompress(&d.header),
-                body:   payload.encoding.decompress(&d.body),
-            })
-            .collect(),
+        Encoding::Snappy | Encoding::Lz4 | Encoding::Zlib | Encoding::Gzip | Encoding::Zstd => {
+            payload
+                .data
+                .par_iter()
+                .map(|d| DataFrame {
+                    header: payload.encoding.decompress(&d.header),
+                    body:   payload.encoding.decompress(&d.body),
+                })
+                .collect()
+        }
         Encoding::None => payload.data,
-        _ => unimplemented!(),
     }
 }
 
@@ -297,6 +363,55 @@ mod tests {
     use std::sync::Arc;
     use std::time::Instant;
 
+    #[test]
+    fn payload_stats() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(arrow::array::Int32Array::from(vec![7; 4096]))],
+        )?;
+
+        let uuid = UuidBuilder::new("SX72HzqFz1Qij4bP-00", 1).next();
+        let (bytes, stats) =
+            Payload::to_bytes_with_stats(&batch, uuid.clone(), Encoding::Zstd, None);
+        assert_eq!(1, stats.payloads);
+        assert_eq!(bytes.len(), stats.encoded_bytes);
+        // 4096 repeated integers compress well.
+        assert!(stats.raw_bytes >= 4096 * 4);
+        assert!(stats.encoded_bytes < stats.raw_bytes);
+        assert_eq!(bytes, Payload::to_bytes(&batch, uuid, Encoding::Zstd));
+
+        let total = stats + stats;
+        assert_eq!(2, total.payloads);
+        assert_eq!(2 * stats.encoded_bytes, total.encoded_bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn payload_watermark() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(arrow::array::Int32Array::from(vec![1, 2, 3]))],
+        )?;
+        let uuid = UuidBuilder::new("SX72HzqFz1Qij4bP-00", 1).next();
+
+        let (bytes, _) =
+            Payload::to_bytes_with_stats(&batch, uuid.clone(), Encoding::Zstd, Some(1_000));
+        let event: Value = serde_json::from_slice(&bytes)?;
+        assert_eq!(Some(1_000), Payload::watermark_of(&event));
+        let (batches, _) = Payload::to_batch(event);
+        assert_eq!(batch.num_rows(), batches[0].num_rows());
+
+        // Payloads without a watermark leave the field out.
+        let event = Payload::to_value(&[batch], uuid, Encoding::Zstd);
+        assert!(event.get("watermark").is_none());
+        assert_eq!(None, Payload::watermark_of(&event));
+
+        Ok(())
+    }
+
     #[test]
     fn uuid_builder() {
         let function_name = "SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836";
@@ -576,6 +691,7 @@ mod tests {
                 schema: Payload::schema_to_bytes(schema.clone()),
                 uuid,
                 encoding: encoding.clone(),
+                watermark: None,
             };
 
             let mut bytes = Vec::new();
@@ -23,12 +23,25 @@
 //! ```
 
 pub use crate::arena::{Arena, WindowSession};
+pub use crate::clock::{Clock, MockClock, SystemClock};
 pub use crate::config;
 pub use crate::config::GLOBALS as globals;
-pub use crate::context::{CloudFunction, ExecutionContext};
-pub use crate::datasource::{kafka, kinesis, nexmark, DataSource};
+pub use crate::context::{
+    CloudFunction, ExecutionContext, BATCH_SIZE_FLAG, EVENT_TIME_FLAG, NO_GROUP_FLAG,
+    ON_ERROR_FLAG, SHUFFLE_HASH_FLAG, UNROUTABLE_FLAG, VALIDATE_FLAG,
+};
+pub use crate::datasink::{DataSinkType, OnError, SinkSummary};
+pub use crate::datasource::{json, kafka, kinesis, nexmark, sqs, DataSource};
 pub use crate::encoding::Encoding;
 pub use crate::error::{Result, SquirtleError};
-pub use crate::executor::{plan::physical_plan, ExecutionStrategy, Executor, LambdaExecutor};
-pub use crate::payload::{Payload, Uuid, UuidBuilder};
+pub use crate::executor::{
+    hash::{ShuffleHash, Unroutable},
+    plan::physical_plan,
+    reshard::{MemberState, Reshard},
+    retry::{RetryStats, RETRY_BUDGET},
+    ExecutionStrategy, Executor, LambdaExecutor,
+};
+pub use crate::logger;
+pub use crate::naming::{group_member, group_members, query_code, FunctionName};
+pub use crate::payload::{Payload, PayloadStats, Uuid, UuidBuilder};
 pub use crate::query::{BatchQuery, Query, Schedule, StreamQuery, StreamWindow};